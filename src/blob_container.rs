@@ -1,15 +1,73 @@
 use anyhow::{Context, Result};
 use azure_core::Bytes;
-use azure_core::time::OffsetDateTime;
+use azure_core::http::headers::ETAG;
+use azure_core::http::{RequestContent, StatusCode};
+use azure_core::time::{OffsetDateTime, to_rfc3339};
 use azure_storage_blob::BlobContainerClient;
-use azure_storage_blob::models::BlobClientDownloadOptions;
+use azure_storage_blob::models::{
+    AppendBlobClientAppendBlockOptions, BlobClientGetPropertiesResultHeaders,
+    BlobClientSetMetadataOptions, BlobType, BlockBlobClientUploadOptions,
+};
 use fuser::{FUSE_ROOT_ID, FileAttr};
-use futures::StreamExt;
-use log::{error, info};
-use std::collections::HashMap;
+use futures::FutureExt;
+use log::{Level, error, info, log_enabled, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
 use std::ops::Range;
-use std::path::Path;
-use std::time::{Instant, SystemTime};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Handle;
+
+use crate::blob_read::{BlobRead, BlockRead, ParallelDownload, Prefetching, assemble};
+use crate::blob_upload::BlobUpload;
+use crate::block_cache::{BlockCache, CACHE_BLOCK_SIZE};
+use crate::disk_cache::DiskCache;
+use crate::hash::hash_name;
+use crate::listing::{
+    DirectoryListing, FOLDER_METADATA, ListedBlob, Listing, MTIME_METADATA, has_folder_metadata,
+    list_container, modification_time,
+};
+use crate::page_blob::{PAGE_SIZE, PageRanges};
+use crate::rest_client::BlobRestClient;
+use crate::snapshot::{Snapshot, SnapshotDirectory, SnapshotFile};
+
+/// Maximum size of a single Append Block call
+pub const APPEND_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Options controlling how the container is exposed through the filesystem
+#[derive(Debug, Clone, Default)]
+pub struct ContainerOptions {
+    /// New files under these prefixes are created as append blobs
+    pub append_prefixes: Vec<String>,
+    /// Reject every change to the container
+    pub read_only: bool,
+    /// Memory budget of the block read cache in bytes
+    pub cache_memory: u64,
+    /// Directory persisting downloaded blocks across mounts
+    pub cache_dir: Option<PathBuf>,
+    /// Maximum size of the disk cache in bytes
+    pub cache_dir_max_size: u64,
+    /// Maximum read-ahead window for sequential reads in bytes, 0 to disable
+    pub readahead: u64,
+    /// Size of the ranges large downloads are split into
+    pub download_chunk_size: u64,
+    /// Maximum number of concurrent ranged downloads per read
+    pub download_concurrency: usize,
+    /// Blobs up to this size are downloaded whole when opened, 0 to disable
+    pub preload_size: u64,
+    /// File persisting the namespace, to mount from it instead of listing first
+    pub snapshot_path: Option<PathBuf>,
+    /// How often the container is listed again to pick up remote changes, zero to disable
+    pub refresh_interval: Duration,
+    /// List directories with a delimiter as they are browsed instead of the whole container
+    pub lazy_listing: bool,
+    /// How long a directory listing is trusted before it is listed again
+    pub listing_ttl: Duration,
+    /// How long a lookup that found nothing on the service is remembered
+    pub negative_ttl: Duration,
+}
 
 /// Represents a blob item in the Azure Storage container
 #[derive(Debug, Clone)]
@@ -18,8 +76,10 @@ pub struct BlobInfo {
     pub size: u64,
     pub last_modified: SystemTime,
     pub inode: u64,
-    pub cache: Option<Bytes>, // Optional data for the blob, can be used for caching
-    pub cache_range: Range<i64>, // Range for cached data
+    pub upload: Option<Box<BlobUpload>>, // Block list while the blob is open for writing
+    pub blob_type: BlobType,
+    pub pages: Option<PageRanges>, // Valid page ranges of a page blob, fetched on first use
+    pub etag: Option<String>,      // None once modified locally until the next commit
 }
 
 impl BlobInfo {
@@ -29,74 +89,273 @@ impl BlobInfo {
             size,
             last_modified,
             inode,
-            cache: None,       // Data can be set later if needed
-            cache_range: 0..0, // Default range, can be updated later
+            upload: None,
+            blob_type: BlobType::BlockBlob,
+            pages: None,
+            etag: None,
         }
     }
 
-    async fn download(&mut self, client: &BlobContainerClient, range: Range<i64>) -> Result<Bytes> {
-        if self.cache.is_some() && self.cache_range == range {
-            info!("Using cached data for blob: {}", self.name);
-            return Ok(self.cache.clone().unwrap_or_default());
+    /// Returns the valid page ranges of a page blob, fetching them on first use
+    async fn page_ranges(&mut self, rest_client: &BlobRestClient) -> Result<&mut PageRanges> {
+        if self.pages.is_none() {
+            self.pages = Some(PageRanges::fetch(rest_client, &self.name).await?);
         }
+        Ok(self.pages.get_or_insert_default())
+    }
 
-        let options = BlobClientDownloadOptions {
-            range: Some(format!("bytes={}-{}", range.start, range.end - 1)),
-            ..Default::default()
-        };
-        let data = client
-            .blob_client(self.name.clone())
-            .download(Some(options))
-            .await
-            .context(format!("Failed to download blob: {}", self.name))?
-            .into_raw_body()
-            .collect()
-            .await?;
-        self.cache = Some(data.clone());
-        Ok(data)
+    /// Writes data at the given offset, opening the blob's block list on first write.
+    ///
+    /// Append blobs only accept writes at their end and page blobs are patched with Put Page.
+    async fn write(
+        &mut self,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        match self.blob_type {
+            BlobType::AppendBlob => return self.append(client, offset, data).await,
+            BlobType::PageBlob => {
+                let name = self.name.clone();
+                let mut size = self.size;
+                self.page_ranges(rest_client)
+                    .await?
+                    .write(client, &name, &mut size, offset, data)
+                    .await?;
+                self.size = size;
+                self.last_modified = SystemTime::now();
+                self.etag = None;
+                return Ok(());
+            }
+            _ => {}
+        }
+        if self.upload.is_none() {
+            self.upload = Some(Box::new(
+                BlobUpload::open(client, &self.name, self.size).await?,
+            ));
+        }
+        if let Some(upload) = self.upload.as_mut() {
+            upload
+                .write(client, rest_client, &self.name, offset, data)
+                .await?;
+            self.size = upload.size();
+        }
+        self.last_modified = SystemTime::now();
+        self.etag = None;
+        Ok(())
+    }
+
+    /// Appends data to an append blob, one Append Block call per chunk.
+    ///
+    /// Writes arrive through the write buffer of their handle, so small writes
+    /// are coalesced into full blocks and don't use up the blob's 50,000 blocks.
+    async fn append(
+        &mut self,
+        client: &BlobContainerClient,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        if offset != self.size {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL)).context(format!(
+                "Append blob {} can only be written at its end ({}), not at {offset}",
+                self.name, self.size
+            ));
+        }
+        let client = client.blob_client(self.name.clone()).append_blob_client();
+        for chunk in data.chunks(APPEND_BLOCK_SIZE) {
+            let options = AppendBlobClientAppendBlockOptions {
+                append_position: Some(self.size as i64),
+                ..Default::default()
+            };
+            client
+                .append_block(
+                    RequestContent::from(chunk.to_vec()),
+                    chunk.len() as u64,
+                    Some(options),
+                )
+                .await
+                .context(format!("Failed to append block to blob: {}", self.name))?;
+            self.size += chunk.len() as u64;
+        }
+        self.last_modified = SystemTime::now();
+        self.etag = None;
+        Ok(())
     }
 
-    /// Synchronous method to download blob content
-    pub fn download_sync(
+    /// Changes the size of the blob, without fetching the block list when truncating to zero
+    async fn truncate(
         &mut self,
         client: &BlobContainerClient,
-        range: Range<i64>,
-    ) -> Result<Bytes> {
-        let runtime = tokio::runtime::Runtime::new()?;
-        runtime.block_on(self.download(client, range))
+        rest_client: &BlobRestClient,
+        size: u64,
+    ) -> Result<()> {
+        match self.blob_type {
+            // Append blobs can only be emptied, by creating them again
+            BlobType::AppendBlob if size == 0 => {
+                client
+                    .blob_client(self.name.clone())
+                    .append_blob_client()
+                    .create(None)
+                    .await
+                    .context(format!("Failed to recreate append blob: {}", self.name))?;
+                self.size = 0;
+                self.last_modified = SystemTime::now();
+                self.etag = None;
+                return Ok(());
+            }
+            // Page blobs can be resized in place, but only to whole pages
+            BlobType::PageBlob => {
+                if !size.is_multiple_of(PAGE_SIZE) {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL)).context(format!(
+                        "Page blob {} can't be resized to {size}, not a multiple of {PAGE_SIZE}",
+                        self.name
+                    ));
+                }
+                client
+                    .blob_client(self.name.clone())
+                    .page_blob_client()
+                    .resize(size, None)
+                    .await
+                    .context(format!("Failed to resize page blob: {}", self.name))?;
+                if let Some(pages) = self.pages.as_mut() {
+                    pages.truncate(size);
+                }
+                self.size = size;
+                self.last_modified = SystemTime::now();
+                self.etag = None;
+                return Ok(());
+            }
+            BlobType::AppendBlob => {
+                return Err(io::Error::from_raw_os_error(libc::ENOTSUP))
+                    .context(format!("Cannot resize {:?} {}", self.blob_type, self.name));
+            }
+            _ => {}
+        }
+        if self.upload.is_none() {
+            self.upload = Some(Box::new(if size == 0 {
+                BlobUpload::empty_keeping_properties(client, &self.name).await?
+            } else {
+                BlobUpload::open(client, &self.name, self.size).await?
+            }));
+        }
+        if let Some(upload) = self.upload.as_mut() {
+            upload
+                .truncate(client, rest_client, &self.name, size)
+                .await?;
+            upload.commit(client, rest_client, &self.name).await?;
+            self.size = upload.size();
+            self.etag = upload.etag().or(self.etag.take());
+        }
+        self.last_modified = SystemTime::now();
+        Ok(())
+    }
+
+    /// Stages modified blocks and commits the block list
+    async fn commit(
+        &mut self,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+    ) -> Result<()> {
+        if let Some(upload) = self.upload.as_mut()
+            && upload.is_dirty()
+        {
+            upload.commit(client, rest_client, &self.name).await?;
+            self.etag = upload.etag();
+        }
+        Ok(())
+    }
+
+    /// Synchronous method to write blob content
+    pub fn write_sync(
+        &mut self,
+        runtime: &Handle,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        runtime.block_on(self.write(client, rest_client, offset, data))
+    }
+
+    /// Synchronous method to change the blob size
+    pub fn truncate_sync(
+        &mut self,
+        runtime: &Handle,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+        size: u64,
+    ) -> Result<()> {
+        runtime.block_on(self.truncate(client, rest_client, size))
+    }
+
+    /// Synchronous method to commit staged blocks
+    pub fn commit_sync(
+        &mut self,
+        runtime: &Handle,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+    ) -> Result<()> {
+        runtime.block_on(self.commit(client, rest_client))
     }
 }
 
 pub struct BlobDirectory {
-    pub entries: HashMap<String, u64>,
+    pub entries: BTreeMap<String, u64>, // Sorted by name, so listings have a stable order
     pub inode: u64,
+    pub marker: Option<String>, // Name of the zero-length hdi_isfolder marker blob, "dir" or "dir/"
+    pub listed_at: Option<Instant>, // Last delimiter listing of the directory, if listed lazily
+    pub modified: Option<SystemTime>, // Newest Last-Modified of its files and marker, or entry change
+    pub size: u64,                    // Total size of its files
+    pub subdirectories: u32,
 }
 
 impl BlobDirectory {
     pub fn new(inode: u64, parent: u64) -> Self {
         Self {
-            entries: HashMap::from([("..".to_string(), parent), (".".to_string(), inode)]),
+            entries: BTreeMap::from([("..".to_string(), parent), (".".to_string(), inode)]),
             inode,
+            marker: None,
+            listed_at: None,
+            modified: None,
+            size: 0,
+            subdirectories: 0,
+        }
+    }
+
+    /// Adds a child, counting what it adds to the directory's attributes
+    pub fn add_child(&mut self, name: String, inode: u64, (size, subdirectories): (u64, u32)) {
+        if self.entries.insert(name, inode).is_none() {
+            self.size += size;
+            self.subdirectories += subdirectories;
         }
     }
 
-    /// Adds a file to the directory
-    pub fn add_file(&mut self, name: String, inode: u64) {
-        self.entries.insert(name, inode);
+    /// Removes a child, if it is there, along with what it added to the attributes
+    pub fn remove_child(&mut self, name: &str, (size, subdirectories): (u64, u32)) {
+        if self.entries.remove(name).is_some() {
+            self.size = self.size.saturating_sub(size);
+            self.subdirectories = self.subdirectories.saturating_sub(subdirectories);
+        }
     }
 
-    /// Checks if the directory is empty
+    /// Checks if the directory is empty, ignoring "." and ".."
     pub fn is_empty(&self) -> bool {
-        self.entries.is_empty()
+        self.entries.keys().all(|name| name == "." || name == "..")
     }
 
     pub fn root() -> Self {
         Self {
-            entries: HashMap::from([
+            entries: BTreeMap::from([
                 ("..".to_string(), FUSE_ROOT_ID),
                 (".".to_string(), FUSE_ROOT_ID),
             ]),
             inode: FUSE_ROOT_ID,
+            marker: None,
+            listed_at: None,
+            modified: None,
+            size: 0,
+            subdirectories: 0,
         }
     }
 }
@@ -106,6 +365,16 @@ pub enum BlobEntry {
     Directory(BlobDirectory), // Represents a directory entry
 }
 
+impl BlobEntry {
+    /// Size and subdirectory count the entry adds to its parent directory
+    fn parent_counts(&self) -> (u64, u32) {
+        match self {
+            BlobEntry::File(blob) => (blob.size, 0),
+            BlobEntry::Directory(_) => (0, 1),
+        }
+    }
+}
+
 impl From<&BlobEntry> for FileAttr {
     fn from(entry: &BlobEntry) -> Self {
         match entry {
@@ -126,14 +395,15 @@ impl From<&BlobEntry> for FileAttr {
                 blksize: 4096,
                 flags: 0, // Owner group ID (can be set to actual group ID)
             },
+            // Times, size and link count depend on the children, see BlobContainer::attributes
             BlobEntry::Directory(dir) => FileAttr {
                 ino: dir.inode, // Directories can have a special inode or use a fixed one
                 size: 0,        // Size is not meaningful for directories
                 blocks: 0,      // No blocks for empty directories
-                atime: SystemTime::now(),
-                mtime: SystemTime::now(),
-                ctime: SystemTime::now(),
-                crtime: SystemTime::now(),
+                atime: UNIX_EPOCH,
+                mtime: UNIX_EPOCH,
+                ctime: UNIX_EPOCH,
+                crtime: UNIX_EPOCH,
                 kind: fuser::FileType::Directory,
                 perm: 0o755, // rwxr-xr-x for directories
                 nlink: 2,    // Directories have at least two links (., ..)
@@ -147,44 +417,182 @@ impl From<&BlobEntry> for FileAttr {
     }
 }
 
+/// Writes a snapshot unless a newer one was written while it waited
+fn write_snapshot(saved: &Mutex<u64>, sequence: u64, snapshot: &Snapshot, path: &Path) {
+    let mut saved = saved.lock().unwrap();
+    if *saved > sequence {
+        return;
+    }
+    match snapshot.save(path) {
+        Ok(()) => {
+            *saved = sequence;
+            info!(
+                "Saved snapshot of {} entries to {}",
+                snapshot.files.len() + snapshot.directories.len(),
+                path.display()
+            );
+        }
+        Err(err) => warn!("Failed to save snapshot: {err:#}"),
+    }
+}
+
+/// Returns the parent path and file name of a blob path
+fn split_path(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Compares ETags from response headers, which are quoted, with ETags from
+/// listings, which are not
+fn same_etag(a: Option<&str>, b: Option<&str>) -> bool {
+    a.map(|etag| etag.trim_matches('"')) == b.map(|etag| etag.trim_matches('"'))
+}
+
+/// How a listed blob changed the namespace
+enum Merged {
+    Added,
+    Updated,
+    Unchanged,
+}
+
 /// Azure blob container wrapper that handles blob operations and caching
 pub struct BlobContainer {
-    container_client: BlobContainerClient,
+    container_client: Arc<BlobContainerClient>,
+    rest_client: Arc<BlobRestClient>,
     // Cache for blob metadata to avoid repeated API calls
     blob_cache: HashMap<String, BlobEntry>,
     inode_map: HashMap<u64, String>,
-    next_inode: u64,
+    // Generation handed to newly allocated inodes, bumped whenever a number is freed
+    generation: u64,
+    // Generation of every live inode allocated after some number was freed
+    generations: HashMap<u64, u64>,
+    // Lookup count of every inode the kernel holds a reference to
+    lookups: HashMap<u64, u64>,
+    // Directory renames that failed part way, keyed by source path
+    pending_renames: HashMap<String, String>,
+    options: ContainerOptions,
+    block_cache: Arc<Mutex<BlockCache>>,
+    disk_cache: Option<Arc<Mutex<DiskCache>>>,
+    // Runtime of the mount, running background prefetches
+    runtime: Handle,
+    // Prefetches in flight, which reads of the same blocks wait for
+    prefetching: Prefetching,
+    // Listings completed in the background, merged on the FUSE thread
+    listing_sender: Sender<Listing>,
+    listings: Receiver<Listing>,
+    // Blobs reads found changed on the service, merged on the FUSE thread
+    changed_sender: Sender<Listing>,
+    changed_blobs: Receiver<Listing>,
+    // Paths recently looked up on the service and not found
+    negative_lookups: HashMap<String, Instant>,
+    // Paths created, removed or renamed through the mount, so listings that
+    // started before the change don't undo it
+    local_changes: HashMap<String, SystemTime>,
+    // Sequence of the last snapshot started and of the newest one written, so a
+    // slow background save never replaces a newer snapshot
+    snapshot_sequence: u64,
+    snapshot_saved: Arc<Mutex<u64>>,
+    // When the namespace was first listed, carried over by snapshots. Reported
+    // for directories with neither files nor marker, until something changes in them
+    first_mounted: SystemTime,
 }
 
 impl BlobContainer {
     /// Creates a new BlobContainer instance
-    pub async fn new(container_client: BlobContainerClient) -> Result<Self> {
+    pub async fn new(
+        container_client: BlobContainerClient,
+        rest_client: BlobRestClient,
+        options: ContainerOptions,
+    ) -> Result<Self> {
         let inode_map = HashMap::from([(FUSE_ROOT_ID, String::new())]);
         let blob_cache =
             HashMap::from([(String::new(), BlobEntry::Directory(BlobDirectory::root()))]);
 
+        let disk_cache = options
+            .cache_dir
+            .as_deref()
+            .map(|dir| DiskCache::open(dir, options.cache_dir_max_size))
+            .transpose()?
+            .map(|cache| Arc::new(Mutex::new(cache)));
+        let (listing_sender, listings) = mpsc::channel();
+        let (changed_sender, changed_blobs) = mpsc::channel();
         let mut container = Self {
-            container_client,
+            container_client: Arc::new(container_client),
+            rest_client: Arc::new(rest_client),
             blob_cache,
             inode_map,
-            next_inode: 2, // Start from 2, as 1 is reserved for root
+            generation: 0,
+            generations: HashMap::new(),
+            lookups: HashMap::new(),
+            pending_renames: HashMap::new(),
+            block_cache: Arc::new(Mutex::new(BlockCache::new(options.cache_memory))),
+            disk_cache,
+            runtime: Handle::current(),
+            prefetching: Arc::default(),
+            listing_sender,
+            listings,
+            changed_sender,
+            changed_blobs,
+            negative_lookups: HashMap::new(),
+            local_changes: HashMap::new(),
+            snapshot_sequence: 0,
+            snapshot_saved: Arc::default(),
+            first_mounted: SystemTime::now(),
+            options,
         };
-        container.load_blobs().await?;
+
+        let snapshot = container
+            .options
+            .snapshot_path
+            .as_deref()
+            .and_then(Snapshot::load);
+        let restored = snapshot.is_some();
+        if let Some(snapshot) = snapshot {
+            container.restore_snapshot(snapshot);
+        }
+        if container.options.lazy_listing {
+            info!("Directories are listed as they are browsed");
+        } else if restored {
+            // Mount right away and catch up with the container in the background
+            container.spawn_listing(true);
+        } else {
+            let listing = list_container(&container.rest_client).await?;
+            container.merge_listing(listing, false);
+            container.save_snapshot();
+            container.spawn_listing(false);
+        }
+        if log_enabled!(Level::Debug) {
+            container.debug_blob_cache();
+        }
         Ok(container)
     }
 
+    /// Returns true if the container was mounted read-only
+    pub fn is_read_only(&self) -> bool {
+        self.options.read_only
+    }
+
+    /// Fails with EROFS on a read-only mount, before any write request is made
+    fn ensure_writable(&self) -> Result<()> {
+        if self.options.read_only {
+            return Err(io::Error::from_raw_os_error(libc::EROFS))
+                .context("Container is mounted read-only");
+        }
+        Ok(())
+    }
+
     fn add_directory(&mut self, name: String, inode: u64, parent: u64) {
         let directory = BlobDirectory::new(inode, parent);
         self.blob_cache
             .insert(name.clone(), BlobEntry::Directory(directory));
         self.inode_map.insert(inode, name.clone());
+        let name = split_path(&name).1.to_string();
 
         self.inode_map
             .get_mut(&parent)
             .and_then(|parent_name| self.blob_cache.get_mut(parent_name))
             .and_then(|entry| {
                 if let BlobEntry::Directory(dir) = entry {
-                    dir.add_file(name, inode);
+                    dir.add_child(name, inode, (0, 1));
                     Some(())
                 } else {
                     None
@@ -192,75 +600,159 @@ impl BlobContainer {
             });
     }
 
-    /// Refreshes the blob cache by listing all blobs in the container
-    async fn load_blobs(&mut self) -> anyhow::Result<()> {
-        info!("Refreshing blob cache from Azure Storage (cache expired or empty)");
-
-        // Record the start time of cache refresh
-        let refresh_start = Instant::now();
-
-        // List all blobs in the container
-        let mut page_stream = self.container_client.list_blobs(None)?;
-
-        while let Some(page_result) = page_stream.next().await {
-            match page_result {
-                Ok(page) => {
-                    let segment = page.into_body().await?.segment;
-                    for blob_item in segment.blob_items {
-                        let blob_name = &blob_item.name.unwrap().content.unwrap();
-                        info!("Processing entry: {blob_name}");
-                        let path = Path::new(&blob_name);
-                        let parent_path = if let Some(p) = path.parent() {
-                            self.process_directories(blob_name);
-                            p.to_str().unwrap_or("")
-                        } else {
-                            ""
-                        };
-
-                        let mut size: u64 = 0;
-                        let mut last_modified: SystemTime = SystemTime::now();
-                        if let Some(properties) = &blob_item.properties {
-                            size = properties.content_length.unwrap_or(0);
-                            last_modified = SystemTime::from(
-                                properties
-                                    .last_modified
-                                    .unwrap_or(OffsetDateTime::now_utc()),
-                            );
-                        }
+    /// Adds a file entry under its parent directory, creating the directories on its path
+    fn insert_file(&mut self, blob: BlobInfo) {
+        self.process_directories(&blob.name);
+        let (parent_path, name) = split_path(&blob.name);
+        if let Some(BlobEntry::Directory(parent_dir)) = self.blob_cache.get_mut(parent_path) {
+            parent_dir.add_child(name.to_string(), blob.inode, (blob.size, 0));
+            parent_dir.modified = parent_dir.modified.max(Some(blob.last_modified));
+        }
+        self.inode_map.insert(blob.inode, blob.name.clone());
+        self.blob_cache
+            .insert(blob.name.clone(), BlobEntry::File(blob));
+    }
 
-                        // Create blob entry
-                        let inode = self.next_inode;
-                        self.next_inode += 1;
-
-                        let blob_info = BlobInfo {
-                            name: blob_name.clone(),
-                            size,
-                            last_modified,
-                            inode,
-                            cache: None,
-                            cache_range: 0..0, // Default range, can be updated later
-                        };
-
-                        self.inode_map.insert(inode, blob_name.clone());
-                        self.blob_cache
-                            .insert(blob_name.clone(), BlobEntry::File(blob_info));
-                        let parent = self.blob_cache.get_mut(parent_path);
-                        if let Some(BlobEntry::Directory(parent_dir)) = parent {
-                            let name = path.file_name().unwrap_or_default().to_string_lossy();
-                            parent_dir.add_file(name.to_string(), inode);
-                        }
-                    }
+    /// Drops an entry given its full path
+    fn remove_path(&mut self, path: &str) {
+        let (parent_path, name) = split_path(path);
+        if let Some(BlobEntry::Directory(parent_dir)) = self.blob_cache.get(parent_path) {
+            let parent = parent_dir.inode;
+            self.remove_entry(parent, name);
+        }
+    }
+
+    /// Adds or updates the entry of a listed blob, keeping the inode of an existing one.
+    ///
+    /// Blobs with uncommitted writes or changed locally since the listing started
+    /// are left as they are.
+    fn merge_blob(&mut self, blob: ListedBlob, started: SystemTime) -> Merged {
+        if self.changed_locally(blob.name.trim_end_matches('/'), started) {
+            return Merged::Unchanged;
+        }
+        if blob.marker {
+            let path = blob.name.trim_end_matches('/');
+            let exists = matches!(
+                self.blob_cache.get(path),
+                Some(BlobEntry::Directory(dir)) if dir.marker.is_some()
+            );
+            self.add_marker_directory(path, blob.name.clone());
+            // The marker's Last-Modified dates directories that hold no files
+            if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(path) {
+                dir.modified = dir.modified.max(Some(blob.last_modified));
+            }
+            return if exists {
+                Merged::Unchanged
+            } else {
+                Merged::Added
+            };
+        }
+        match self.blob_cache.get_mut(&blob.name) {
+            Some(BlobEntry::File(info)) => {
+                if info.upload.is_some() || same_etag(info.etag.as_deref(), blob.etag.as_deref()) {
+                    return Merged::Unchanged;
                 }
-                Err(e) => {
-                    error!("Error listing blobs: {e}");
-                    return Err(e).context("Failed to list blobs from Azure Storage");
+                info!("Blob {} changed on the service", blob.name);
+                let old_size = info.size;
+                info.size = blob.size;
+                info.last_modified = blob.last_modified;
+                info.etag = blob.etag;
+                info.blob_type = blob.blob_type;
+                info.pages = None;
+                let inode = info.inode;
+                self.invalidate_caches(inode);
+                self.file_changed(inode, old_size);
+                Merged::Updated
+            }
+            Some(BlobEntry::Directory(_)) => {
+                warn!("Skipping blob {} named like a directory", blob.name);
+                Merged::Unchanged
+            }
+            None => {
+                let inode = self.allocate_inode(&blob.name);
+                self.insert_file(BlobInfo {
+                    name: blob.name,
+                    size: blob.size,
+                    last_modified: blob.last_modified,
+                    inode,
+                    upload: None,
+                    blob_type: blob.blob_type,
+                    pages: None,
+                    etag: blob.etag,
+                });
+                Merged::Added
+            }
+        }
+    }
+
+    /// Merges a listing of the whole container into the namespace.
+    ///
+    /// With `refresh` the listing updates an existing namespace, so blobs it adds
+    /// change their directory, like removed ones do.
+    fn merge_listing(&mut self, listing: Listing, refresh: bool) {
+        let (mut added, mut updated, mut removed) = (0, 0, 0);
+        let mut listed = HashSet::new();
+        for blob in listing.blobs {
+            let path = blob.name.trim_end_matches('/').to_string();
+            match self.merge_blob(blob, listing.started) {
+                Merged::Added if refresh => {
+                    self.touch_parent(&path, listing.started);
+                    added += 1
+                }
+                Merged::Added => added += 1,
+                Merged::Updated => updated += 1,
+                Merged::Unchanged => {}
+            }
+            listed.insert(path);
+        }
+
+        let deleted: Vec<String> = self
+            .blob_cache
+            .iter()
+            .filter_map(|(path, entry)| match entry {
+                BlobEntry::File(blob)
+                    if !listed.contains(path)
+                        && blob.upload.is_none()
+                        && !self.changed_locally(path, listing.started) =>
+                {
+                    Some(path.clone())
                 }
+                _ => None,
+            })
+            .collect();
+        for path in deleted {
+            info!("Blob {path} was deleted on the service");
+            self.remove_path(&path);
+            removed += 1;
+        }
+
+        // Directories left without blobs or marker, deepest first
+        let mut directories: Vec<String> = self
+            .blob_cache
+            .iter()
+            .filter(|(path, entry)| !path.is_empty() && matches!(entry, BlobEntry::Directory(_)))
+            .map(|(path, _)| path.clone())
+            .collect();
+        directories.sort_by_key(|path| std::cmp::Reverse(path.len()));
+        for path in directories {
+            if self.changed_locally(&path, listing.started) {
+                continue;
+            }
+            let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(&path) else {
+                continue;
+            };
+            if dir.marker.is_some() && !listed.contains(&path) {
+                dir.marker = None;
+            }
+            if dir.marker.is_none() && dir.is_empty() {
+                self.remove_path(&path);
+                removed += 1;
             }
         }
+        self.forget_changes_before(listing.started);
 
-        let refresh_duration = refresh_start.elapsed();
         info!(
-            "Blob cache refreshed with {} entries ({} blobs, {} directories) in {:.2}s",
+            "Merged listing: {added} added, {updated} updated, {removed} removed, {} entries ({} blobs, {} directories)",
             self.blob_cache.len(),
             self.blob_cache
                 .values()
@@ -270,11 +762,415 @@ impl BlobContainer {
                 .values()
                 .filter(|b| matches!(b, BlobEntry::Directory(_)))
                 .count(),
-            refresh_duration.as_secs_f64()
         );
+    }
+
+    /// Merges a delimiter listing of one directory, keeping the inode of every existing entry
+    fn merge_directory(&mut self, path: &str, listing: DirectoryListing) {
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{path}/")
+        };
+        // Entries new to a directory listed before were added since
+        let refresh = matches!(
+            self.blob_cache.get(path),
+            Some(BlobEntry::Directory(dir)) if dir.listed_at.is_some()
+        );
+        let mut listed = HashSet::new();
+        for blob in listing.blobs {
+            listed.insert(blob.name[prefix.len()..].trim_end_matches('/').to_string());
+            if let (Merged::Added, true) = (self.merge_blob(blob, listing.started), refresh) {
+                self.touch_path(path, listing.started);
+            }
+        }
+        for directory in listing.directories {
+            listed.insert(directory[prefix.len()..].to_string());
+            if !self.blob_cache.contains_key(&directory)
+                && !self.changed_locally(&directory, listing.started)
+            {
+                self.process_directories(&format!("{directory}/"));
+                if refresh {
+                    self.touch_path(path, listing.started);
+                }
+            }
+        }
+
+        // Children that are gone from the service, unless they changed locally
+        let Some(BlobEntry::Directory(dir)) = self.blob_cache.get(path) else {
+            return;
+        };
+        let gone: Vec<String> = dir
+            .entries
+            .keys()
+            .filter(|name| *name != "." && *name != ".." && !listed.contains(*name))
+            .map(|name| format!("{prefix}{name}"))
+            .collect();
+        for child in gone {
+            let child_prefix = format!("{child}/");
+            let changed = self.blob_cache.iter().any(|(key, entry)| {
+                (*key == child || key.starts_with(&child_prefix))
+                    && matches!(entry, BlobEntry::File(blob)
+                        if blob.upload.is_some() || self.changed_locally(key, listing.started))
+            });
+            if changed || self.changed_locally(&child, listing.started) {
+                continue;
+            }
+            info!("{child} was deleted on the service");
+            if matches!(self.blob_cache.get(&child), Some(BlobEntry::File(_))) {
+                self.remove_path(&child);
+            } else {
+                self.remove_subtree(&child);
+            }
+        }
+        self.forget_changes_before(listing.started);
+    }
+
+    /// Remembers that a path was created, written, removed or renamed through the mount.
+    ///
+    /// Listings compare against these instead of Last-Modified, which may be an
+    /// mtime set by the user.
+    fn record_change(&mut self, path: &str) {
+        self.local_changes
+            .insert(path.to_string(), SystemTime::now());
+    }
+
+    /// Returns true if the path or one of its parents changed through the mount
+    /// at or after `since`, so a listing started then may not reflect it
+    fn changed_locally(&self, path: &str, since: SystemTime) -> bool {
+        if self.local_changes.is_empty() {
+            return false;
+        }
+        let mut path = path;
+        loop {
+            if self
+                .local_changes
+                .get(path)
+                .is_some_and(|changed| *changed >= since)
+            {
+                return true;
+            }
+            match path.rfind('/') {
+                Some(end) => path = &path[..end],
+                None => return false,
+            }
+        }
+    }
+
+    /// Drops the changes that every listing from now on reflects
+    fn forget_changes_before(&mut self, started: SystemTime) {
+        self.local_changes.retain(|_, changed| *changed >= started);
+    }
+
+    /// Lists a lazily listed directory on first use and again once its listing is
+    /// older than the listing TTL
+    pub fn refresh_directory(&mut self, inode: u64) -> Result<()> {
+        if !self.options.lazy_listing {
+            return Ok(());
+        }
+        let Some(path) = self.inode_map.get(&inode).cloned() else {
+            return Ok(());
+        };
+        match self.blob_cache.get(&path) {
+            Some(BlobEntry::Directory(dir))
+                if dir
+                    .listed_at
+                    .is_none_or(|listed_at| listed_at.elapsed() >= self.options.listing_ttl) => {}
+            _ => return Ok(()),
+        }
+
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{path}/")
+        };
+        info!("Listing directory {path:?}");
+        let listing =
+            self.runtime
+                .block_on(self.rest_client.list_blobs(&prefix, Some("/"), None))?;
+        self.merge_directory(&path, listing);
+        self.recount(&path);
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(&path) {
+            dir.listed_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Recomputes the size and subdirectory count of a directory from its
+    /// entries, which eviction keeps while dropping the entries themselves
+    fn recount(&mut self, path: &str) {
+        let Some(BlobEntry::Directory(dir)) = self.blob_cache.get(path) else {
+            return;
+        };
+        let (size, subdirectories) = dir
+            .entries
+            .iter()
+            .filter(|(name, _)| *name != "." && *name != "..")
+            .filter_map(|(_, inode)| self.get_entry_by_inode(*inode))
+            .map(BlobEntry::parent_counts)
+            .fold((0, 0), |(size, dirs), (s, d)| (size + s, dirs + d));
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(path) {
+            dir.size = size;
+            dir.subdirectories = subdirectories;
+        }
+    }
+
+    /// Lists every blob below a lazily listed directory before it is changed as a whole
+    fn load_subtree(&mut self, path: &str) -> Result<()> {
+        if !self.options.lazy_listing {
+            return Ok(());
+        }
+        let listing =
+            self.runtime
+                .block_on(self.rest_client.list_blobs(&format!("{path}/"), None, None))?;
+        for blob in listing.blobs {
+            self.merge_blob(blob, listing.started);
+        }
         Ok(())
     }
 
+    /// Looks for a child missing from the namespace on the service, first as a blob
+    /// and then as a directory with a one-result listing of its prefix.
+    ///
+    /// Anything found is added to the namespace. Misses are remembered for the
+    /// negative TTL so repeated lookups don't reach the service.
+    pub fn lookup_missing(&mut self, parent: u64, name: &str) -> Result<()> {
+        let Some(path) = self.child_path(parent, name) else {
+            return Ok(());
+        };
+        if self
+            .negative_lookups
+            .get(&path)
+            .is_some_and(|missed| missed.elapsed() < self.options.negative_ttl)
+        {
+            return Ok(());
+        }
+
+        info!("Looking up {path} on the service");
+        let client = self.container_client.blob_client(path.clone());
+        match self.runtime.block_on(client.get_properties(None)) {
+            Ok(properties) => {
+                let size = properties.content_length()?.unwrap_or(0);
+                let metadata = properties.metadata()?;
+                let blob = ListedBlob {
+                    name: path.clone(),
+                    size,
+                    last_modified: modification_time(
+                        &metadata,
+                        properties
+                            .last_modified()?
+                            .unwrap_or_else(OffsetDateTime::now_utc),
+                    ),
+                    etag: properties.etag()?,
+                    blob_type: properties.blob_type()?.unwrap_or(BlobType::BlockBlob),
+                    marker: size == 0 && has_folder_metadata(&metadata),
+                };
+                if let Merged::Added = self.merge_blob(blob, SystemTime::now()) {
+                    self.touch_parent(&path, SystemTime::now());
+                }
+                self.negative_lookups.remove(&path);
+                return Ok(());
+            }
+            Err(err) if err.http_status() == Some(StatusCode::NotFound) => {}
+            Err(err) => {
+                return Err(err).context(format!("Failed to get properties for blob: {path}"));
+            }
+        }
+
+        let listing = self.runtime.block_on(self.rest_client.list_blobs(
+            &format!("{path}/"),
+            Some("/"),
+            Some(1),
+        ))?;
+        if !listing.blobs.is_empty() || !listing.directories.is_empty() {
+            self.process_directories(&format!("{path}/"));
+            self.touch_parent(&path, SystemTime::now());
+            self.negative_lookups.remove(&path);
+            return Ok(());
+        }
+
+        let negative_ttl = self.options.negative_ttl;
+        self.negative_lookups
+            .retain(|_, missed| missed.elapsed() < negative_ttl);
+        self.negative_lookups.insert(path, Instant::now());
+        Ok(())
+    }
+
+    /// Lists the container on the runtime, right away if `list_now` is set and then
+    /// every refresh interval. Listings are merged by a later apply_listings.
+    fn spawn_listing(&self, list_now: bool) {
+        let interval = self.options.refresh_interval;
+        if !list_now && interval.is_zero() {
+            return;
+        }
+        let rest_client = self.rest_client.clone();
+        let sender = self.listing_sender.clone();
+        self.runtime.spawn(async move {
+            let mut list_now = list_now;
+            loop {
+                if !list_now {
+                    tokio::time::sleep(interval).await;
+                }
+                list_now = false;
+                match list_container(&rest_client).await {
+                    Ok(listing) => {
+                        // The container is gone once the filesystem is unmounted
+                        if sender.send(listing).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) => warn!("Background listing failed: {err:#}"),
+                }
+                if interval.is_zero() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Merges the background listings completed since the last call
+    pub fn apply_listings(&mut self) {
+        let mut merged = self.merge_changed_blobs();
+        while let Ok(listing) = self.listings.try_recv() {
+            self.merge_listing(listing, true);
+            merged = true;
+        }
+        if merged {
+            self.save_snapshot();
+        }
+    }
+
+    /// Merges the blobs reads found changed on the service since the last call,
+    /// so later reads ask for their current version
+    pub fn apply_changed_blobs(&mut self) {
+        if self.merge_changed_blobs() {
+            self.save_snapshot();
+        }
+    }
+
+    fn merge_changed_blobs(&mut self) -> bool {
+        let mut merged = false;
+        while let Ok(changed) = self.changed_blobs.try_recv() {
+            // Entries removed or renamed since the read started are left alone
+            for blob in changed.blobs {
+                if matches!(self.blob_cache.get(&blob.name), Some(BlobEntry::File(_)))
+                    && let Merged::Updated = self.merge_blob(blob, changed.started)
+                {
+                    merged = true;
+                }
+            }
+        }
+        merged
+    }
+
+    /// Rebuilds the namespace from a snapshot, with the inodes it was saved with
+    fn restore_snapshot(&mut self, mut snapshot: Snapshot) {
+        // Parents sort before their children
+        snapshot.directories.sort_by(|a, b| a.name.cmp(&b.name));
+        for directory in snapshot.directories {
+            self.process_directories(&directory.name);
+            let (parent_path, _) = split_path(&directory.name);
+            let Some(BlobEntry::Directory(parent_dir)) = self.blob_cache.get(parent_path) else {
+                continue;
+            };
+            let parent = parent_dir.inode;
+            self.add_directory(directory.name.clone(), directory.inode, parent);
+            if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(&directory.name) {
+                dir.marker = directory.marker;
+                dir.modified = dir.modified.max(directory.modified);
+            }
+            if directory.generation > 0 {
+                self.generations
+                    .insert(directory.inode, directory.generation);
+            }
+        }
+        for file in snapshot.files {
+            if file.generation > 0 {
+                self.generations.insert(file.inode, file.generation);
+            }
+            self.insert_file(BlobInfo {
+                name: file.name,
+                size: file.size,
+                last_modified: file.last_modified,
+                inode: file.inode,
+                upload: None,
+                blob_type: file.blob_type,
+                pages: None,
+                etag: file.etag,
+            });
+        }
+        self.generation = snapshot.generation;
+        self.first_mounted = snapshot.first_mounted.unwrap_or(self.first_mounted);
+    }
+
+    /// Copies the namespace into a snapshot
+    fn snapshot(&self) -> Snapshot {
+        let mut directories = Vec::new();
+        let mut files = Vec::new();
+        for (name, entry) in &self.blob_cache {
+            match entry {
+                BlobEntry::Directory(dir) if !name.is_empty() => {
+                    directories.push(SnapshotDirectory {
+                        name: name.clone(),
+                        inode: dir.inode,
+                        marker: dir.marker.clone(),
+                        generation: self.generation(dir.inode),
+                        modified: dir.modified,
+                    })
+                }
+                BlobEntry::Directory(_) => {}
+                BlobEntry::File(blob) => files.push(SnapshotFile {
+                    name: name.clone(),
+                    inode: blob.inode,
+                    size: blob.size,
+                    last_modified: blob.last_modified,
+                    etag: blob.etag.clone(),
+                    blob_type: blob.blob_type,
+                    generation: self.generation(blob.inode),
+                }),
+            }
+        }
+        Snapshot::new(self.generation, self.first_mounted, directories, files)
+    }
+
+    /// Saves a snapshot of the namespace in the background, serialized and written
+    /// on a blocking task so the FUSE thread only pays for the copy
+    pub fn save_snapshot(&mut self) {
+        let Some(path) = self.options.snapshot_path.clone() else {
+            return;
+        };
+        self.snapshot_sequence += 1;
+        let (sequence, saved, snapshot) = (
+            self.snapshot_sequence,
+            self.snapshot_saved.clone(),
+            self.snapshot(),
+        );
+        self.runtime
+            .spawn_blocking(move || write_snapshot(&saved, sequence, &snapshot, &path));
+    }
+
+    /// Saves a snapshot of the namespace before returning, when unmounting
+    pub fn save_snapshot_now(&mut self) {
+        let Some(path) = self.options.snapshot_path.clone() else {
+            return;
+        };
+        self.snapshot_sequence += 1;
+        write_snapshot(
+            &self.snapshot_saved,
+            self.snapshot_sequence,
+            &self.snapshot(),
+            &path,
+        );
+    }
+
+    /// Adds a directory that is persisted by the marker blob `marker`
+    fn add_marker_directory(&mut self, path: &str, marker: String) {
+        self.process_directories(&format!("{path}/"));
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(path) {
+            dir.marker = Some(marker);
+        }
+    }
+
     /// Processes directories for a given blob name, creating directory entries as needed
     pub fn process_directories(&mut self, blob_name: &str) {
         let mut parent_inode = FUSE_ROOT_ID;
@@ -285,13 +1181,955 @@ impl BlobContainer {
             if let Some(BlobEntry::Directory(dir)) = entry {
                 parent_inode = dir.inode;
             } else {
-                self.add_directory(dir_path, self.next_inode, parent_inode);
-                parent_inode = self.next_inode;
-                self.next_inode += 1;
+                let inode = self.allocate_inode(&dir_path);
+                self.add_directory(dir_path, inode, parent_inode);
+                parent_inode = inode;
             }
         }
     }
 
+    /// Derives a stable inode number from a path, probing past numbers in use.
+    ///
+    /// Numbers freed by removed entries can come back for another path, under
+    /// a higher generation so the kernel and NFS clients can tell them apart.
+    /// Entries keep their number when renamed, but only a snapshot carries it
+    /// over to the next mount, which otherwise derives it from the new path.
+    fn allocate_inode(&mut self, path: &str) -> u64 {
+        let mut inode = hash_name(path);
+        while inode <= FUSE_ROOT_ID || self.inode_map.contains_key(&inode) {
+            inode = inode.wrapping_add(1);
+        }
+        if self.generation > 0 {
+            self.generations.insert(inode, self.generation);
+        }
+        inode
+    }
+
+    /// Frees the inode number of a removed entry
+    fn release_inode(&mut self, inode: u64) {
+        self.inode_map.remove(&inode);
+        self.generations.remove(&inode);
+        self.generation += 1;
+        // Blocks of this owner must not be served to the next one
+        self.block_cache.lock().unwrap().invalidate(inode);
+    }
+
+    /// Generation of an inode number, higher than that of any earlier owner of the number
+    pub fn generation(&self, inode: u64) -> u64 {
+        self.generations.get(&inode).copied().unwrap_or(0)
+    }
+
+    /// Counts a reference handed to the kernel by a lookup, create or mkdir reply
+    pub fn remember(&mut self, inode: u64) {
+        *self.lookups.entry(inode).or_default() += 1;
+    }
+
+    /// Drops kernel references, returning true once the inode is no longer referenced
+    pub fn forget(&mut self, inode: u64, nlookup: u64) -> bool {
+        let Some(count) = self.lookups.get_mut(&inode) else {
+            return false;
+        };
+        *count = count.saturating_sub(nlookup);
+        if *count > 0 {
+            return false;
+        }
+        self.lookups.remove(&inode);
+        true
+    }
+
+    /// Releases the metadata of an inode the kernel no longer references.
+    ///
+    /// Files drop their page ranges. With lazy listing the children of a
+    /// directory are dropped as well and listed again on next use, unless
+    /// something below it is still referenced or has uncommitted writes.
+    /// Without it the namespace is only refreshed by full listings, which
+    /// would not bring dropped entries back, so directories are kept.
+    pub fn evict(&mut self, inode: u64) {
+        let Some(path) = self.inode_map.get(&inode).cloned() else {
+            return;
+        };
+        match self.blob_cache.get_mut(&path) {
+            Some(BlobEntry::File(blob)) => blob.pages = None,
+            Some(BlobEntry::Directory(_)) if self.options.lazy_listing => {
+                self.evict_children(&path)
+            }
+            _ => {}
+        }
+    }
+
+    fn evict_children(&mut self, path: &str) {
+        let mut children = Vec::new();
+        self.collect_subtree(path, &mut children);
+        let pinned = children.iter().any(|(key, inode)| {
+            self.lookups.contains_key(inode)
+                || matches!(self.blob_cache.get(key), Some(BlobEntry::File(blob)) if blob.upload.is_some())
+        });
+        if pinned || children.is_empty() {
+            return;
+        }
+
+        info!("Evicting {} entries below {path:?}", children.len());
+        // Inode numbers are kept, the same paths get them back when listed again.
+        // Blocks cached under them would then outlive a change on the service.
+        let mut block_cache = self.block_cache.lock().unwrap();
+        for (key, inode) in children {
+            if let Some(BlobEntry::File(_)) = self.blob_cache.remove(&key) {
+                block_cache.invalidate(inode);
+            }
+            self.inode_map.remove(&inode);
+        }
+        drop(block_cache);
+        // Modification time, size and link count are kept so stat doesn't change,
+        // the counts are recomputed when the directory is listed again
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(path) {
+            dir.entries.retain(|name, _| name == "." || name == "..");
+            dir.listed_at = None;
+        }
+    }
+
+    /// Collects the paths and inodes of everything below a directory
+    fn collect_subtree(&self, path: &str, children: &mut Vec<(String, u64)>) {
+        let Some(BlobEntry::Directory(dir)) = self.blob_cache.get(path) else {
+            return;
+        };
+        for (name, &inode) in &dir.entries {
+            if name == "." || name == ".." {
+                continue;
+            }
+            let Some(child) = self.inode_map.get(&inode) else {
+                continue;
+            };
+            children.push((child.clone(), inode));
+            self.collect_subtree(child, children);
+        }
+    }
+
+    /// Returns the full blob path for a child of the given directory
+    fn child_path(&self, parent: u64, name: &str) -> Option<String> {
+        let parent_path = self.inode_map.get(&parent)?;
+        if parent_path.is_empty() {
+            Some(name.to_string())
+        } else {
+            Some(format!("{parent_path}/{name}"))
+        }
+    }
+
+    /// Creates a new, empty file entry that is immediately visible to lookup and readdir.
+    ///
+    /// Files opened with O_APPEND or created under an append prefix become append
+    /// blobs, which are created on the service right away.
+    pub fn create_file(&mut self, parent: u64, name: &str, append: bool) -> Result<u64> {
+        self.ensure_writable()?;
+        if self.get_directory(parent).is_none() {
+            anyhow::bail!("Parent inode {parent} is not a directory");
+        }
+        let blob_name = self
+            .child_path(parent, name)
+            .ok_or_else(|| anyhow::format_err!("Parent inode {parent} not found"))?;
+        if self.blob_cache.contains_key(&blob_name) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST))
+                .context(format!("Blob {blob_name} already exists"));
+        }
+
+        let inode = self.allocate_inode(&blob_name);
+
+        let mut blob_info = BlobInfo::new(blob_name.clone(), 0, SystemTime::now(), inode);
+        let append = append || self.is_append_path(&blob_name);
+        if append {
+            self.runtime
+                .block_on(
+                    self.container_client
+                        .blob_client(blob_name.clone())
+                        .append_blob_client()
+                        .create(None),
+                )
+                .context(format!("Failed to create append blob: {blob_name}"))?;
+            blob_info.blob_type = BlobType::AppendBlob;
+        } else {
+            blob_info.upload = Some(Box::new(BlobUpload::new()));
+        }
+        self.inode_map.insert(inode, blob_name.clone());
+        self.blob_cache
+            .insert(blob_name.clone(), BlobEntry::File(blob_info));
+        if let Some(BlobEntry::Directory(dir)) = self
+            .inode_map
+            .get(&parent)
+            .and_then(|path| self.blob_cache.get_mut(path))
+        {
+            dir.add_child(name.to_string(), inode, (0, 0));
+        }
+        self.touch_directory(parent);
+        self.record_change(&blob_name);
+        info!("Created blob entry: {blob_name} (inode {inode})");
+        Ok(inode)
+    }
+
+    /// Prepares a blob opened for writing. An empty block blob opened with O_APPEND,
+    /// or under an append prefix, becomes an append blob so appends are Append Block calls.
+    ///
+    /// Blobs with content keep their type. Appends to a block blob are staged as
+    /// new blocks without downloading what the blob already holds.
+    pub fn open_for_writing(&mut self, inode: u64, append: bool) -> Result<()> {
+        self.ensure_writable()?;
+        let Some(blob_name) = self.inode_map.get(&inode) else {
+            return Ok(());
+        };
+        if !append && !self.is_append_path(blob_name) {
+            return Ok(());
+        }
+        let entry = self.blob_cache.get_mut(blob_name);
+        let Some(BlobEntry::File(blob)) = entry else {
+            return Ok(());
+        };
+        if blob.blob_type != BlobType::BlockBlob || blob.size != 0 || blob.upload.is_some() {
+            return Ok(());
+        }
+        info!("Recreating empty blob {} as an append blob", blob.name);
+        self.runtime
+            .block_on(
+                self.container_client
+                    .blob_client(blob.name.clone())
+                    .append_blob_client()
+                    .create(None),
+            )
+            .context(format!("Failed to create append blob: {}", blob.name))?;
+        blob.blob_type = BlobType::AppendBlob;
+        blob.last_modified = SystemTime::now();
+        blob.etag = None;
+        let blob_name = blob.name.clone();
+        self.record_change(&blob_name);
+        self.file_changed(inode, 0);
+        Ok(())
+    }
+
+    /// Returns true if new files at this path are created as append blobs
+    fn is_append_path(&self, blob_name: &str) -> bool {
+        self.options
+            .append_prefixes
+            .iter()
+            .any(|prefix| blob_name.starts_with(prefix.as_str()))
+    }
+
+    /// Drops the cached blocks of a blob whose content is changing
+    fn invalidate_caches(&mut self, inode: u64) {
+        self.block_cache.lock().unwrap().invalidate(inode);
+        if let Some((disk_cache, name)) = self.disk_cache.as_ref().zip(self.inode_map.get(&inode)) {
+            let garbage = disk_cache.lock().unwrap().invalidate(name);
+            self.remove_cache_files(garbage);
+        }
+    }
+
+    /// Removes files dropped from the disk cache without blocking the FUSE thread
+    fn remove_cache_files(&self, garbage: Vec<PathBuf>) {
+        if !garbage.is_empty() {
+            self.runtime
+                .spawn_blocking(move || DiskCache::remove_paths(&garbage));
+        }
+    }
+
+    /// Checks cached blocks against the blob's current version when it is opened
+    pub fn open_blob(&mut self, inode: u64) {
+        let entry = self
+            .inode_map
+            .get(&inode)
+            .and_then(|blob_name| self.blob_cache.get(blob_name));
+        if let Some((disk_cache, BlobEntry::File(blob))) = self.disk_cache.as_ref().zip(entry) {
+            let garbage = disk_cache.lock().unwrap().validate(
+                &blob.name,
+                blob.etag.as_deref(),
+                blob.last_modified,
+            );
+            self.remove_cache_files(garbage);
+        }
+    }
+
+    /// Starts downloading a small committed blob whole through the caches, keyed by its ETag.
+    ///
+    /// Returns None for blobs above the preload size or with uncommitted changes.
+    pub fn preload_blob(&mut self, inode: u64) -> Result<Option<BlobRead>> {
+        let Some(BlobEntry::File(blob)) = self.get_entry_by_inode(inode) else {
+            return Ok(None);
+        };
+        if blob.size == 0 || blob.size > self.options.preload_size || blob.etag.is_none() {
+            return Ok(None);
+        }
+        let Ok(size) = u32::try_from(blob.size) else {
+            return Ok(None);
+        };
+        info!("Preloading blob {} ({size} bytes)", blob.name);
+        self.start_read(inode, 0, size).map(Some)
+    }
+
+    /// Writes data to a blob that is open for writing
+    pub fn write_blob(&mut self, inode: u64, offset: i64, data: &[u8]) -> Result<u32> {
+        self.ensure_writable()?;
+        info!("Writing blob: {inode} {offset} {}", data.len());
+        self.invalidate_caches(inode);
+        let entry = self
+            .inode_map
+            .get(&inode)
+            .and_then(|blob_name| self.blob_cache.get_mut(blob_name));
+
+        if let Some(BlobEntry::File(blob)) = entry {
+            let old_size = blob.size;
+            let result = blob.write_sync(
+                &self.runtime,
+                &self.container_client,
+                &self.rest_client,
+                offset as u64,
+                data,
+            );
+            let blob_name = blob.name.clone();
+            self.record_change(&blob_name);
+            self.file_changed(inode, old_size);
+            result?;
+            Ok(data.len() as u32)
+        } else {
+            Err(anyhow::format_err!("Blob with inode {} not found", inode))
+        }
+    }
+
+    /// Commits everything written so far with Put Block List.
+    ///
+    /// A file unlinked while open has nothing left to commit, so a missing
+    /// inode counts as flushed and closing it doesn't fail.
+    pub fn flush_blob(&mut self, inode: u64) -> Result<()> {
+        let entry = self
+            .inode_map
+            .get(&inode)
+            .and_then(|blob_name| self.blob_cache.get_mut(blob_name));
+
+        match entry {
+            Some(BlobEntry::File(blob)) => {
+                let old_size = blob.size;
+                let dirty = blob.upload.as_ref().is_some_and(|upload| upload.is_dirty());
+                let result =
+                    blob.commit_sync(&self.runtime, &self.container_client, &self.rest_client);
+                // Blocks read while the writes were uncommitted hold the data they replaced
+                if dirty {
+                    let blob_name = blob.name.clone();
+                    self.record_change(&blob_name);
+                    self.invalidate_caches(inode);
+                }
+                self.file_changed(inode, old_size);
+                result
+            }
+            Some(BlobEntry::Directory(_)) => Err(io::Error::from_raw_os_error(libc::EISDIR))
+                .context(format!("Inode {inode} is not a file")),
+            None => {
+                info!("Blob with inode {inode} is gone, nothing to flush");
+                Ok(())
+            }
+        }
+    }
+
+    /// Truncates or extends a blob, committing the new size right away.
+    ///
+    /// The block list is kept only if the file is open, otherwise it is dropped
+    /// again since no release will follow.
+    pub fn set_blob_size(&mut self, inode: u64, size: u64, is_open: bool) -> Result<()> {
+        self.ensure_writable()?;
+        info!("Setting blob size: {inode} {size}");
+        self.invalidate_caches(inode);
+        let entry = self
+            .inode_map
+            .get(&inode)
+            .and_then(|blob_name| self.blob_cache.get_mut(blob_name));
+
+        if let Some(BlobEntry::File(blob)) = entry {
+            let keep_upload = is_open || blob.upload.is_some();
+            let old_size = blob.size;
+            let result = blob.truncate_sync(
+                &self.runtime,
+                &self.container_client,
+                &self.rest_client,
+                size,
+            );
+            if result.is_ok() && !keep_upload {
+                blob.upload = None;
+            }
+            let blob_name = blob.name.clone();
+            self.record_change(&blob_name);
+            self.file_changed(inode, old_size);
+            result
+        } else {
+            Err(io::Error::from_raw_os_error(libc::EISDIR))
+                .context(format!("Inode {inode} is not a file"))
+        }
+    }
+
+    /// Keeps a modification time in the mtime metadata of a blob, keeping its
+    /// other metadata. Returns the new metadata and the blob's new ETag.
+    fn store_mtime(
+        &self,
+        name: &str,
+        last_modified: SystemTime,
+    ) -> Result<(HashMap<String, String>, Option<String>)> {
+        let client = self.container_client.blob_client(name.to_string());
+        let mut metadata = self
+            .runtime
+            .block_on(client.get_properties(None))
+            .context(format!("Failed to get properties for blob: {name}"))?
+            .metadata()?;
+        metadata.insert(
+            MTIME_METADATA.to_string(),
+            to_rfc3339(&OffsetDateTime::from(last_modified)),
+        );
+        let options = BlobClientSetMetadataOptions {
+            metadata: Some(metadata.clone()),
+            ..Default::default()
+        };
+        let response = self
+            .runtime
+            .block_on(client.set_metadata(Some(options)))
+            .context(format!("Failed to set metadata of blob: {name}"))?;
+        Ok((metadata, response.headers().get_optional_string(&ETAG)))
+    }
+
+    /// Sets the modification time of a blob or directory.
+    ///
+    /// Last-Modified is owned by the service, so the time is kept in the blob's
+    /// mtime metadata, which listings report instead. Directories keep it in
+    /// their marker blob. Virtual directories have no blob to keep it in, so
+    /// the call is accepted without changing anything, like chmod and chown.
+    pub fn set_last_modified(&mut self, inode: u64, last_modified: SystemTime) -> Result<()> {
+        self.ensure_writable()?;
+        match self.get_entry_by_inode(inode) {
+            Some(BlobEntry::File(_)) => {}
+            Some(BlobEntry::Directory(dir)) => {
+                let Some(marker) = dir.marker.clone() else {
+                    return Ok(());
+                };
+                self.store_mtime(&marker, last_modified)?;
+                if let Some(dir) = self.directory_mut(inode) {
+                    dir.modified = Some(last_modified);
+                }
+                return Ok(());
+            }
+            None => {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT))
+                    .context(format!("Inode {inode} not found"));
+            }
+        }
+
+        // Put Block List replaces the metadata, so pending writes are committed first
+        self.flush_blob(inode)?;
+        let Some(BlobEntry::File(blob)) = self.get_entry_by_inode(inode) else {
+            return Ok(());
+        };
+        let (metadata, etag) = self.store_mtime(&blob.name, last_modified)?;
+        let Some(BlobEntry::File(blob)) = self
+            .inode_map
+            .get(&inode)
+            .and_then(|blob_name| self.blob_cache.get_mut(blob_name))
+        else {
+            return Ok(());
+        };
+        let old_size = blob.size;
+        // Later commits of an open upload would otherwise put the old metadata back
+        if let Some(upload) = blob.upload.as_mut() {
+            upload.set_metadata(metadata);
+        }
+        // The new ETag keeps the next listing from reporting the blob as changed
+        blob.etag = etag;
+        blob.last_modified = last_modified;
+        let blob_name = blob.name.clone();
+        self.record_change(&blob_name);
+        self.file_changed(inode, old_size);
+        Ok(())
+    }
+
+    /// Commits pending data and ends the upload for the blob, if it wasn't unlinked
+    pub fn release_blob(&mut self, inode: u64) -> Result<()> {
+        self.flush_blob(inode)?;
+        if let Some(BlobEntry::File(blob)) = self
+            .inode_map
+            .get(&inode)
+            .and_then(|blob_name| self.blob_cache.get_mut(blob_name))
+        {
+            blob.upload = None;
+        }
+        Ok(())
+    }
+
+    /// Removes a child from its parent directory, blob_cache and inode_map
+    fn remove_entry(&mut self, parent: u64, name: &str) -> Option<BlobEntry> {
+        let blob_name = self.child_path(parent, name)?;
+        let entry = self.blob_cache.remove(&blob_name);
+        if let Some(dir) = self.directory_mut(parent) {
+            match &entry {
+                Some(entry) => dir.remove_child(name, entry.parent_counts()),
+                None => {
+                    dir.entries.remove(name);
+                }
+            }
+        }
+        let entry = entry?;
+        self.touch_directory(parent);
+        match &entry {
+            BlobEntry::File(blob) => {
+                self.invalidate_caches(blob.inode);
+                self.release_inode(blob.inode);
+            }
+            BlobEntry::Directory(dir) => self.release_inode(dir.inode),
+        }
+        Some(entry)
+    }
+
+    /// Deletes a blob from the container and drops it from the namespace
+    pub fn delete_file(&mut self, parent: u64, name: &str) -> Result<()> {
+        self.ensure_writable()?;
+        let blob_name = self
+            .child_path(parent, name)
+            .ok_or_else(|| anyhow::format_err!("Parent inode {parent} not found"))?;
+        let uncommitted = matches!(
+            self.blob_cache.get(&blob_name),
+            Some(BlobEntry::File(BlobInfo {
+                upload: Some(_),
+                ..
+            }))
+        );
+
+        info!("Deleting blob: {blob_name}");
+        let client = self.container_client.blob_client(blob_name.clone());
+        match self.runtime.block_on(client.delete(None)) {
+            Ok(_) => {}
+            // A newly created file may not have been committed yet
+            Err(err) if uncommitted && err.http_status() == Some(StatusCode::NotFound) => {}
+            Err(err) => {
+                return Err(err).context(format!("Failed to delete blob: {blob_name}"));
+            }
+        }
+        self.remove_entry(parent, name);
+        self.record_change(&blob_name);
+        Ok(())
+    }
+
+    /// Creates a directory persisted by a zero-length hdi_isfolder marker blob
+    pub fn create_directory(&mut self, parent: u64, name: &str) -> Result<u64> {
+        self.ensure_writable()?;
+        if self.get_directory(parent).is_none() {
+            anyhow::bail!("Parent inode {parent} is not a directory");
+        }
+        let blob_name = self
+            .child_path(parent, name)
+            .ok_or_else(|| anyhow::format_err!("Parent inode {parent} not found"))?;
+        if self.blob_cache.contains_key(&blob_name) {
+            return Err(io::Error::from_raw_os_error(libc::EEXIST))
+                .context(format!("{blob_name} already exists"));
+        }
+
+        info!("Creating directory marker: {blob_name}");
+        let options = BlockBlobClientUploadOptions {
+            metadata: Some(HashMap::from([(
+                FOLDER_METADATA.to_string(),
+                "true".to_string(),
+            )])),
+            ..Default::default()
+        };
+        let client = self.container_client.blob_client(blob_name.clone());
+        self.runtime
+            .block_on(client.upload(RequestContent::from(Vec::new()), false, 0, Some(options)))
+            .context(format!("Failed to create directory marker: {blob_name}"))?;
+
+        let inode = self.allocate_inode(&blob_name);
+        self.add_directory(blob_name.clone(), inode, parent);
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(&blob_name) {
+            dir.marker = Some(blob_name.clone());
+        }
+        self.touch_directory(parent);
+        self.record_change(&blob_name);
+        Ok(inode)
+    }
+
+    /// Removes an empty directory, deleting its marker blob if it has one
+    pub fn remove_directory(&mut self, parent: u64, name: &str) -> Result<()> {
+        self.ensure_writable()?;
+        let blob_name = self
+            .child_path(parent, name)
+            .ok_or_else(|| anyhow::format_err!("Parent inode {parent} not found"))?;
+        // A directory that was never listed may still have blobs on the service
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get(&blob_name) {
+            self.refresh_directory(dir.inode)?;
+        }
+        let marker = match self.blob_cache.get(&blob_name) {
+            Some(BlobEntry::Directory(dir)) if dir.is_empty() => dir.marker.clone(),
+            Some(BlobEntry::Directory(_)) => {
+                return Err(io::Error::from_raw_os_error(libc::ENOTEMPTY))
+                    .context(format!("Directory {blob_name} is not empty"));
+            }
+            Some(BlobEntry::File(_)) => {
+                return Err(io::Error::from_raw_os_error(libc::ENOTDIR))
+                    .context(format!("{blob_name} is not a directory"));
+            }
+            None => {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT))
+                    .context(format!("Directory {blob_name} not found"));
+            }
+        };
+        info!("Removing directory: {blob_name}");
+        if let Some(marker) = marker {
+            let client = self.container_client.blob_client(marker.clone());
+            match self.runtime.block_on(client.delete(None)) {
+                Ok(_) => {}
+                Err(err) if err.http_status() == Some(StatusCode::NotFound) => {}
+                Err(err) => {
+                    return Err(err)
+                        .context(format!("Failed to delete directory marker: {marker}"));
+                }
+            }
+        }
+        self.remove_entry(parent, name);
+        self.record_change(&blob_name);
+        Ok(())
+    }
+
+    fn directory_mut(&mut self, inode: u64) -> Option<&mut BlobDirectory> {
+        match self
+            .inode_map
+            .get(&inode)
+            .and_then(|path| self.blob_cache.get_mut(path))
+        {
+            Some(BlobEntry::Directory(dir)) => Some(dir),
+            _ => None,
+        }
+    }
+
+    /// Records that entries of a directory were added or removed
+    fn touch_directory(&mut self, inode: u64) {
+        if let Some(dir) = self.directory_mut(inode) {
+            dir.modified = dir.modified.max(Some(SystemTime::now()));
+        }
+    }
+
+    /// Moves the modification time of a directory forward to `time`
+    fn touch_path(&mut self, path: &str, time: SystemTime) {
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(path) {
+            dir.modified = dir.modified.max(Some(time));
+        }
+    }
+
+    /// Moves the modification time of the directory holding a path forward to `time`
+    fn touch_parent(&mut self, path: &str, time: SystemTime) {
+        self.touch_path(split_path(path).0, time);
+    }
+
+    /// Updates the parent directory once the size or Last-Modified of a file changed
+    fn file_changed(&mut self, inode: u64, old_size: u64) {
+        let Some(BlobEntry::File(blob)) = self.get_entry_by_inode(inode) else {
+            return;
+        };
+        let (size, last_modified) = (blob.size, blob.last_modified);
+        let parent_path = split_path(&blob.name).0.to_string();
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(&parent_path) {
+            dir.size = (dir.size + size).saturating_sub(old_size);
+            dir.modified = dir.modified.max(Some(last_modified));
+        }
+    }
+
+    /// Attributes of an entry. A directory reports the newest Last-Modified of its
+    /// files and marker, a link per subdirectory and the total size of its files.
+    pub fn attributes(&self, entry: &BlobEntry) -> FileAttr {
+        let mut attr = FileAttr::from(entry);
+        let BlobEntry::Directory(dir) = entry else {
+            return attr;
+        };
+        let modified = dir.modified.unwrap_or(self.first_mounted);
+        attr.atime = modified;
+        attr.mtime = modified;
+        attr.ctime = modified;
+        attr.crtime = modified;
+        attr.nlink = 2 + dir.subdirectories;
+        // Only a hint, blocks stay 0 so du doesn't count the files twice
+        attr.size = dir.size;
+        attr
+    }
+
+    /// Returns true if an earlier directory rename between these names failed part way
+    pub fn is_pending_rename(
+        &self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> bool {
+        match (
+            self.child_path(parent, name),
+            self.child_path(new_parent, new_name),
+        ) {
+            (Some(source), Some(destination)) => {
+                self.pending_renames.get(&source) == Some(&destination)
+            }
+            _ => false,
+        }
+    }
+
+    /// Renames a file or a whole virtual directory with Copy Blob and delete.
+    ///
+    /// Inode numbers are preserved, across remounts only with a snapshot. If a
+    /// directory rename fails part way the blobs moved so far are reflected in
+    /// the namespace, the error is returned and repeating the same rename
+    /// resumes with the remaining blobs.
+    pub fn rename(
+        &mut self,
+        parent: u64,
+        name: &str,
+        new_parent: u64,
+        new_name: &str,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let (Some(source), Some(destination)) = (
+            self.child_path(parent, name),
+            self.child_path(new_parent, new_name),
+        ) else {
+            anyhow::bail!("Parent inode not found for rename of {name}");
+        };
+        if destination.starts_with(&format!("{source}/")) {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL))
+                .context(format!("Cannot move {source} into itself"));
+        }
+        let is_directory = matches!(self.blob_cache.get(&source), Some(BlobEntry::Directory(_)));
+        info!("Renaming {source} -> {destination}");
+        if is_directory {
+            self.load_subtree(&source)?;
+        }
+
+        // Blobs under the source with their destination names, in a stable order.
+        // Directories are moved by their marker blob, whose name may end in a slash.
+        let prefix = format!("{source}/");
+        let mut blobs: Vec<(String, String)> = self
+            .blob_cache
+            .iter()
+            .filter(|(path, _)| **path == source || (is_directory && path.starts_with(&prefix)))
+            .filter_map(|(path, entry)| match entry {
+                BlobEntry::File(_) => Some(path.clone()),
+                BlobEntry::Directory(dir) => dir.marker.clone(),
+            })
+            .map(|blob| {
+                let moved = format!("{destination}{}", &blob[source.len()..]);
+                (blob, moved)
+            })
+            .collect();
+        blobs.sort();
+
+        // Uncommitted writes must reach the service before they can be copied
+        for (path, _) in &blobs {
+            if let Some(BlobEntry::File(blob)) = self.blob_cache.get(path) {
+                let inode = blob.inode;
+                self.flush_blob(inode)?;
+            }
+        }
+
+        // An empty directory being replaced loses its marker, unless a copy overwrites it
+        let replaced_marker = match self.blob_cache.get(&destination) {
+            Some(BlobEntry::Directory(dir)) => dir
+                .marker
+                .clone()
+                .filter(|marker| !blobs.iter().any(|(_, to)| to == marker)),
+            _ => None,
+        };
+        if let Some(marker) = replaced_marker {
+            info!("Deleting directory marker {marker} replaced by {source}");
+            let client = self.container_client.blob_client(marker.clone());
+            match self.runtime.block_on(client.delete(None)) {
+                Ok(_) => {}
+                Err(err) if err.http_status() == Some(StatusCode::NotFound) => {}
+                Err(err) => {
+                    return Err(err)
+                        .context(format!("Failed to delete directory marker: {marker}"));
+                }
+            }
+            if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(&destination) {
+                dir.marker = None;
+            }
+            self.record_change(&destination);
+        }
+
+        let mut moved = Vec::new();
+        let mut failure = None;
+        for (from, to) in blobs.iter() {
+            let result = self.runtime.block_on(async {
+                let etag = self.rest_client.copy_blob(from, to).await?;
+                self.container_client
+                    .blob_client(from.clone())
+                    .delete(None)
+                    .await
+                    .context(format!("Failed to delete blob: {from}"))?;
+                anyhow::Ok(etag)
+            });
+            match result {
+                Ok(etag) => moved.push((from.clone(), to.clone(), etag)),
+                Err(err) => {
+                    failure = Some(err);
+                    break;
+                }
+            }
+        }
+
+        if !moved.is_empty() {
+            self.touch_directory(parent);
+            self.touch_directory(new_parent);
+            self.record_change(&source);
+            self.record_change(&destination);
+        }
+        let resuming = self.pending_renames.get(&source) == Some(&destination);
+        if failure.is_none() && !resuming {
+            // Replace whatever the destination was, then move the subtree as a whole
+            self.remove_subtree(&destination);
+            self.move_subtree(&source, &destination, new_parent, new_name);
+            self.set_copied_etags(&moved);
+            return Ok(());
+        }
+
+        // Move blob entries one at a time so the namespace matches the service
+        for (from, to, _) in &moved {
+            if let Some(BlobEntry::Directory(dir)) =
+                self.blob_cache.get_mut(from.trim_end_matches('/'))
+            {
+                dir.marker = None;
+                self.add_marker_directory(to.trim_end_matches('/'), to.clone());
+            } else {
+                self.move_file_entry(from, to);
+            }
+        }
+        self.set_copied_etags(&moved);
+        match failure {
+            Some(err) if !is_directory => Err(err),
+            Some(err) => {
+                self.pending_renames
+                    .insert(source.clone(), destination.clone());
+                error!(
+                    "Rename of {source} to {destination} failed after moving {} of {} blobs, repeat the rename to resume",
+                    moved.len(),
+                    blobs.len()
+                );
+                Err(err.context(format!(
+                    "Rename of {source} to {destination} is incomplete ({} of {} blobs moved)",
+                    moved.len(),
+                    blobs.len()
+                )))
+            }
+            None => {
+                info!("Resumed rename of {source} to {destination} completed");
+                self.pending_renames.remove(&source);
+                self.remove_subtree(&source);
+                Ok(())
+            }
+        }
+    }
+
+    /// Gives moved files the ETag of their copy, so reads pinned to it match the new blob
+    fn set_copied_etags(&mut self, moved: &[(String, String, Option<String>)]) {
+        for (_, to, etag) in moved {
+            if let Some(BlobEntry::File(blob)) = self.blob_cache.get_mut(to) {
+                blob.etag = etag.clone();
+            }
+        }
+    }
+
+    /// Drops a path and everything below it from blob_cache and inode_map
+    fn remove_subtree(&mut self, path: &str) {
+        let counts = self.blob_cache.get(path).map(BlobEntry::parent_counts);
+        let prefix = format!("{path}/");
+        let paths: Vec<String> = self
+            .blob_cache
+            .keys()
+            .filter(|key| *key == path || key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for key in paths {
+            if let Some(entry) = self.blob_cache.remove(&key) {
+                match entry {
+                    BlobEntry::File(blob) => self.release_inode(blob.inode),
+                    BlobEntry::Directory(dir) => self.release_inode(dir.inode),
+                }
+            }
+        }
+        let (parent_path, name) = split_path(path);
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(parent_path) {
+            match counts {
+                Some(counts) => {
+                    dir.remove_child(name, counts);
+                    dir.modified = dir.modified.max(Some(SystemTime::now()));
+                }
+                None => {
+                    dir.entries.remove(name);
+                }
+            }
+        }
+    }
+
+    /// Re-keys a path and everything below it, keeping every inode number
+    fn move_subtree(&mut self, source: &str, destination: &str, new_parent: u64, new_name: &str) {
+        let prefix = format!("{source}/");
+        let paths: Vec<String> = self
+            .blob_cache
+            .keys()
+            .filter(|key| *key == source || key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        let mut moved = None;
+        for key in paths {
+            let new_key = format!("{destination}{}", &key[source.len()..]);
+            if let Some(mut entry) = self.blob_cache.remove(&key) {
+                let entry_inode = match &mut entry {
+                    BlobEntry::File(blob) => {
+                        blob.name = new_key.clone();
+                        blob.inode
+                    }
+                    BlobEntry::Directory(dir) => {
+                        if let Some(marker) = dir.marker.as_mut() {
+                            *marker = format!("{destination}{}", &marker[source.len()..]);
+                        }
+                        dir.inode
+                    }
+                };
+                if key == source {
+                    if let BlobEntry::Directory(dir) = &mut entry {
+                        dir.entries.insert("..".to_string(), new_parent);
+                    }
+                    moved = Some((entry_inode, entry.parent_counts()));
+                }
+                self.inode_map.insert(entry_inode, new_key.clone());
+                self.blob_cache.insert(new_key, entry);
+            }
+        }
+
+        let Some((inode, counts)) = moved else {
+            return;
+        };
+        let (parent_path, name) = split_path(source);
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(parent_path) {
+            dir.remove_child(name, counts);
+        }
+        if let Some(dir) = self.directory_mut(new_parent) {
+            dir.add_child(new_name.to_string(), inode, counts);
+        }
+    }
+
+    /// Moves a single file entry, creating destination directories as needed
+    fn move_file_entry(&mut self, source: &str, destination: &str) {
+        let Some(BlobEntry::File(mut blob)) = self.blob_cache.remove(source) else {
+            return;
+        };
+        let (parent_path, name) = split_path(source);
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(parent_path) {
+            dir.remove_child(name, (blob.size, 0));
+        }
+
+        self.remove_subtree(destination);
+        self.process_directories(destination);
+        let (inode, size) = (blob.inode, blob.size);
+        blob.name = destination.to_string();
+        self.inode_map.insert(inode, destination.to_string());
+        self.blob_cache
+            .insert(destination.to_string(), BlobEntry::File(blob));
+        let (parent_path, name) = split_path(destination);
+        if let Some(BlobEntry::Directory(dir)) = self.blob_cache.get_mut(parent_path) {
+            dir.add_child(name.to_string(), inode, (size, 0));
+        }
+    }
+
     /// Gets blob info by inode
     pub fn get_entry_by_inode(&self, inode: u64) -> Option<&BlobEntry> {
         self.inode_map
@@ -307,21 +2145,169 @@ impl BlobContainer {
             })
     }
 
-    /// Downloads blob content
-    pub fn download_blob(&mut self, inode: u64, offset: i64, size: u32) -> Result<Bytes> {
-        info!("Downloading blob: {inode} {offset} {size}");
-        let entry = self
+    fn parallel_download(&self) -> ParallelDownload {
+        ParallelDownload {
+            chunk_size: self.options.download_chunk_size,
+            concurrency: self.options.download_concurrency,
+        }
+    }
+
+    /// Returns the maximum read-ahead window for sequential reads
+    pub fn readahead(&self) -> u64 {
+        self.options.readahead
+    }
+
+    /// A read of `range` through the block and disk caches, to run on the runtime
+    fn block_read(
+        &self,
+        blob: &BlobInfo,
+        range: Range<u64>,
+        pages: Option<PageRanges>,
+        generation: u64,
+    ) -> BlockRead {
+        BlockRead {
+            client: self.container_client.blob_client(blob.name.clone()),
+            name: blob.name.clone(),
+            inode: blob.inode,
+            size: blob.size,
+            range,
+            version: blob.etag.clone().map(|etag| (etag, blob.last_modified)),
+            pages,
+            generation,
+            download: self.parallel_download(),
+            block_cache: self.block_cache.clone(),
+            disk_cache: self.disk_cache.clone(),
+            prefetching: self.prefetching.clone(),
+            changed: self.changed_sender.clone(),
+            overlay: None,
+        }
+    }
+
+    /// Downloads the blocks of `start..start + length` into the caches in the background.
+    ///
+    /// Blocks that are cached or already being fetched are skipped, and nothing is
+    /// prefetched while the blob has uncommitted writes. Reads of blocks in flight
+    /// wait for the prefetch instead of downloading them again.
+    pub fn prefetch(&self, inode: u64, start: u64, length: u64) {
+        let Some(BlobEntry::File(blob)) = self.get_entry_by_inode(inode) else {
+            return;
+        };
+        let dirty = blob.upload.as_ref().is_some_and(|upload| upload.is_dirty());
+        let end = (start + length).min(blob.size);
+        if dirty || blob.blob_type == BlobType::PageBlob || start >= end {
+            return;
+        }
+
+        let block_cache = self.block_cache.lock().unwrap();
+        let mut prefetching = self.prefetching.lock().unwrap();
+        let missing: Vec<u64> = BlockCache::block_range(start, end)
+            .filter(|&index| {
+                !block_cache.contains(inode, index) && !prefetching.contains_key(&(inode, index))
+            })
+            .collect();
+        let Some((&first, &last)) = missing.first().zip(missing.last()) else {
+            return;
+        };
+        info!("Prefetching blocks {first}..={last} of blob {}", blob.name);
+
+        let range = first * CACHE_BLOCK_SIZE..((last + 1) * CACHE_BLOCK_SIZE).min(blob.size);
+        let read = self.block_read(blob, range, None, block_cache.generation(inode));
+        // Blocks in between that are cached already are left alone
+        let blocks = (first..=last)
+            .map(|index| (!missing.contains(&index)).then(Bytes::new))
+            .collect();
+        let registered: Vec<(u64, u64)> = missing.iter().map(|&index| (inode, index)).collect();
+        let registry = self.prefetching.clone();
+        let task = async move {
+            if let Err(err) = read.fetch(blocks).await {
+                warn!(
+                    "Failed to prefetch {:?} of blob {}: {err}",
+                    read.range, read.name
+                );
+            }
+            let mut prefetching = registry.lock().unwrap();
+            for index in missing {
+                prefetching.remove(&(inode, index));
+            }
+        }
+        .boxed()
+        .shared();
+        prefetching.extend(registered.into_iter().map(|key| (key, task.clone())));
+        self.runtime.spawn(task);
+    }
+
+    /// Starts a read through the block caches.
+    ///
+    /// Uncommitted writes are served or committed here, reads fully cached in
+    /// memory complete right away and anything else is returned as a
+    /// [`BlockRead`] to run on the runtime.
+    pub fn start_read(&mut self, inode: u64, offset: i64, size: u32) -> Result<BlobRead> {
+        info!("Reading blob: {inode} {offset} {size}");
+        let Some(BlobEntry::File(blob)) = self.get_entry_by_inode(inode) else {
+            return Err(anyhow::format_err!("Blob with inode {} not found", inode));
+        };
+        let end = (offset + size as i64).min(blob.size as i64);
+        if offset >= end {
+            return Ok(BlobRead::Ready(Bytes::new()));
+        }
+        let range = offset as u64..end as u64;
+
+        // Uncommitted writes are served locally, laid over what the service has.
+        // Staged blocks can't be downloaded, so only those are committed first.
+        let mut overlay = None;
+        if let Some(upload) = blob.upload.as_ref().filter(|upload| upload.is_dirty()) {
+            if let Some(data) = upload.read_local(range.clone()) {
+                return Ok(BlobRead::Ready(data));
+            }
+            overlay = upload.overlay(range.clone());
+            if overlay.is_none() {
+                self.flush_blob(inode)?;
+            }
+        }
+
+        let fetched = overlay
+            .as_ref()
+            .map_or(range, |overlay| overlay.committed.clone());
+        let indices = BlockCache::block_range(fetched.start, fetched.end);
+        let (blocks, generation) = {
+            let mut block_cache = self.block_cache.lock().unwrap();
+            let blocks: Vec<Option<Bytes>> = indices
+                .clone()
+                .map(|index| block_cache.get(inode, index))
+                .collect();
+            (blocks, block_cache.generation(inode))
+        };
+        if blocks.iter().all(Option::is_some) {
+            let data = assemble(fetched, indices.start, blocks);
+            return Ok(BlobRead::Ready(match &overlay {
+                Some(overlay) => overlay.apply(&data),
+                None => data,
+            }));
+        }
+
+        let Some(BlobEntry::File(blob)) = self
             .inode_map
             .get(&inode)
-            .and_then(|blob_name| self.blob_cache.get_mut(blob_name));
-
-        if let Some(BlobEntry::File(blob)) = entry {
-            let end = offset + size as i64;
-            let data = blob.download_sync(&self.container_client, Range { start: offset, end })?;
-            Ok(data)
+            .and_then(|blob_name| self.blob_cache.get_mut(blob_name))
+        else {
+            return Err(anyhow::format_err!("Blob with inode {inode} not found"));
+        };
+        let pages = if blob.blob_type == BlobType::PageBlob {
+            let pages = self.runtime.block_on(blob.page_ranges(&self.rest_client))?;
+            Some(pages.clone())
         } else {
-            Err(anyhow::format_err!("Blob with inode {} not found", inode))
+            None
+        };
+        let Some(BlobEntry::File(blob)) = self.get_entry_by_inode(inode) else {
+            return Err(anyhow::format_err!("Blob with inode {inode} not found"));
+        };
+        let mut read = self.block_read(blob, fetched, pages, generation);
+        if let Some(overlay) = overlay {
+            // Only blocks the service has are downloaded
+            read.size = overlay.committed_size;
+            read.overlay = Some(overlay);
         }
+        Ok(BlobRead::Fetch(Box::new(read)))
     }
 
     /// Debug function to print the entries in the blob_cache in detail
@@ -332,8 +2318,8 @@ impl BlobContainer {
             match entry {
                 BlobEntry::File(blob) => {
                     info!(
-                        "File: Path: {}, Inode: {}, Size: {}, Last Modified: {:?}",
-                        path, blob.inode, blob.size, blob.last_modified
+                        "File: Path: {}, Inode: {}, Size: {}, Type: {:?}, Last Modified: {:?}",
+                        path, blob.inode, blob.size, blob.blob_type, blob.last_modified
                     );
                 }
                 BlobEntry::Directory(dir) => {
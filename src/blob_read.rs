@@ -0,0 +1,411 @@
+use anyhow::{Context, Result};
+use azure_core::Bytes;
+use azure_core::http::StatusCode;
+use azure_core::time::OffsetDateTime;
+use azure_storage_blob::BlobClient;
+use azure_storage_blob::models::{
+    BlobClientDownloadOptions, BlobClientGetPropertiesResultHeaders, BlobType,
+};
+use futures::future::{BoxFuture, Shared, join_all};
+use futures::{StreamExt, TryStreamExt, stream};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::blob_upload::Overlay;
+use crate::block_cache::{BlockCache, CACHE_BLOCK_SIZE};
+use crate::disk_cache::{DiskCache, PendingBlock};
+use crate::listing::{ListedBlob, Listing, modification_time};
+use crate::page_blob::PageRanges;
+
+/// Prefetch in flight, awaited by reads that need its blocks
+pub type Prefetch = Shared<BoxFuture<'static, ()>>;
+
+/// Prefetches in flight, keyed by (inode, block index)
+pub type Prefetching = Arc<Mutex<HashMap<(u64, u64), Prefetch>>>;
+
+/// Downloads a range of a blob with a single ranged GET.
+///
+/// With an ETag the download fails instead of returning data of another version.
+pub async fn download_range(
+    client: &BlobClient,
+    name: &str,
+    range: Range<u64>,
+    etag: Option<&str>,
+) -> Result<Bytes> {
+    let options = BlobClientDownloadOptions {
+        range: Some(format!("bytes={}-{}", range.start, range.end - 1)),
+        // ETags from listings come without the quotes the header needs
+        if_match: etag.map(|etag| {
+            if etag.starts_with('"') {
+                etag.to_string()
+            } else {
+                format!("\"{etag}\"")
+            }
+        }),
+        ..Default::default()
+    };
+    let data = client
+        .download(Some(options))
+        .await
+        .context(format!("Failed to download blob: {name}"))?
+        .into_raw_body()
+        .collect()
+        .await?;
+    Ok(data)
+}
+
+/// How large ranges are split into concurrent ranged downloads
+#[derive(Debug, Clone, Copy)]
+pub struct ParallelDownload {
+    pub chunk_size: u64,
+    pub concurrency: usize,
+}
+
+impl ParallelDownload {
+    /// Downloads a range in chunks, keeping up to `concurrency` requests in flight
+    /// and reassembling the chunks in order.
+    pub async fn download(
+        &self,
+        client: &BlobClient,
+        name: &str,
+        range: Range<u64>,
+        etag: Option<&str>,
+    ) -> Result<Bytes> {
+        let chunk_size = self.chunk_size.max(1);
+        if range.end - range.start <= chunk_size {
+            return download_range(client, name, range, etag).await;
+        }
+
+        let chunks: Vec<Range<u64>> = (range.start..range.end)
+            .step_by(chunk_size as usize)
+            .map(|start| start..(start + chunk_size).min(range.end))
+            .collect();
+        info!(
+            "Downloading {range:?} of blob {name} in {} chunks",
+            chunks.len()
+        );
+        let parts: Vec<Bytes> = stream::iter(chunks)
+            .map(|chunk| download_range(client, name, chunk, etag))
+            .buffered(self.concurrency.max(1))
+            .try_collect()
+            .await?;
+
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        for part in parts {
+            data.extend_from_slice(&part);
+        }
+        Ok(Bytes::from(data))
+    }
+}
+
+/// Returns true if a request failed because the blob no longer has the ETag it was pinned to
+fn is_precondition_failed(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<azure_core::Error>()
+        .and_then(|err| err.http_status())
+        == Some(StatusCode::PreconditionFailed)
+}
+
+/// Copies `range` out of the cache blocks covering it
+pub fn assemble(range: Range<u64>, first_index: u64, blocks: Vec<Option<Bytes>>) -> Bytes {
+    let mut data = Vec::with_capacity((range.end - range.start) as usize);
+    for (index, block) in (first_index..).zip(blocks) {
+        let block = block.unwrap_or_default();
+        let block_start = index * CACHE_BLOCK_SIZE;
+        let from = (range.start.max(block_start) - block_start) as usize;
+        let to = ((range.end.min(block_start + CACHE_BLOCK_SIZE) - block_start) as usize)
+            .min(block.len());
+        data.extend_from_slice(&block[from.min(to)..to]);
+    }
+    Bytes::from(data)
+}
+
+/// Outcome of starting a read on the FUSE thread
+pub enum BlobRead {
+    /// Served from memory without any request
+    Ready(Bytes),
+    /// Needs blocks from disk or the service, completed on the runtime
+    Fetch(Box<BlockRead>),
+}
+
+/// A read of a blob through the block caches that owns everything it needs,
+/// so it can run on the runtime while the FUSE thread handles other requests.
+pub struct BlockRead {
+    pub client: BlobClient,
+    pub name: String,
+    pub inode: u64,
+    pub size: u64,
+    pub range: Range<u64>,
+    pub version: Option<(String, SystemTime)>, // ETag and Last-Modified of a committed blob
+    pub pages: Option<PageRanges>,             // Valid ranges if this is a page blob
+    pub generation: u64,
+    pub download: ParallelDownload,
+    pub block_cache: Arc<Mutex<BlockCache>>,
+    pub disk_cache: Option<Arc<Mutex<DiskCache>>>,
+    pub prefetching: Prefetching,
+    pub changed: Sender<Listing>, // Blobs found changed on the service, merged on the FUSE thread
+    pub overlay: Option<Overlay>, // Uncommitted writes laid over the downloaded range
+}
+
+impl BlockRead {
+    /// Reads the range, downloading each run of missing blocks with a single request.
+    ///
+    /// If the blob changed on the service since it was listed, its current
+    /// version is looked up and read instead.
+    pub async fn run(mut self) -> Result<Bytes> {
+        let data = match self.read().await {
+            Err(err) if is_precondition_failed(&err) => {
+                self.reload().await?;
+                self.read().await
+            }
+            result => result,
+        }?;
+        Ok(match &self.overlay {
+            Some(overlay) => overlay.apply(&data),
+            None => data,
+        })
+    }
+
+    /// Picks up the current version of a blob that changed on the service,
+    /// dropping the blocks cached for the old one
+    async fn reload(&mut self) -> Result<()> {
+        let started = SystemTime::now();
+        let properties = self
+            .client
+            .get_properties(None)
+            .await
+            .context(format!("Failed to get properties for blob: {}", self.name))?;
+        let metadata = properties.metadata()?;
+        let blob = ListedBlob {
+            name: self.name.clone(),
+            size: properties.content_length()?.unwrap_or(0),
+            last_modified: modification_time(
+                &metadata,
+                properties
+                    .last_modified()?
+                    .unwrap_or_else(OffsetDateTime::now_utc),
+            ),
+            etag: properties.etag()?,
+            blob_type: properties.blob_type()?.unwrap_or(BlobType::BlockBlob),
+            marker: false,
+        };
+        info!(
+            "Blob {} changed on the service, reading version {:?}",
+            self.name, blob.etag
+        );
+
+        {
+            let mut block_cache = self.block_cache.lock().unwrap();
+            // Blocks of the new version may only be cached if nothing else invalidated the old ones
+            let current = block_cache.generation(self.inode) == self.generation;
+            block_cache.invalidate(self.inode);
+            if current {
+                self.generation = block_cache.generation(self.inode);
+            }
+        }
+        if let Some(disk_cache) = &self.disk_cache {
+            let garbage = disk_cache.lock().unwrap().invalidate(&self.name);
+            tokio::task::spawn_blocking(move || DiskCache::remove_paths(&garbage));
+        }
+
+        self.size = blob.size;
+        self.range.end = self.range.end.min(blob.size);
+        self.range.start = self.range.start.min(self.range.end);
+        self.version = blob.etag.clone().map(|etag| (etag, blob.last_modified));
+        // The namespace is updated once the FUSE thread merges the change
+        let _ = self.changed.send(Listing {
+            blobs: vec![blob],
+            started,
+        });
+        Ok(())
+    }
+
+    /// Reads the range through the caches
+    async fn read(&self) -> Result<Bytes> {
+        let indices = BlockCache::block_range(self.range.start, self.range.end);
+        let mut blocks = self.cached_blocks(&indices, Vec::new());
+
+        // Blocks still being prefetched are in the memory cache once the prefetch completes
+        let pending: Vec<Prefetch> = {
+            let prefetching = self.prefetching.lock().unwrap();
+            indices
+                .clone()
+                .zip(&blocks)
+                .filter(|(_, block)| block.is_none())
+                .filter_map(|(index, _)| prefetching.get(&(self.inode, index)).cloned())
+                .collect()
+        };
+        if !pending.is_empty() {
+            join_all(pending).await;
+            blocks = self.cached_blocks(&indices, blocks);
+        }
+
+        let blocks = self.fetch(blocks).await?;
+        Ok(assemble(self.range.clone(), indices.start, blocks))
+    }
+
+    /// Looks up the blocks of the range that are not known yet in the memory cache
+    fn cached_blocks(&self, indices: &Range<u64>, known: Vec<Option<Bytes>>) -> Vec<Option<Bytes>> {
+        let mut block_cache = self.block_cache.lock().unwrap();
+        indices
+            .clone()
+            .zip(known.into_iter().chain(std::iter::repeat(None)))
+            .map(|(index, block)| block.or_else(|| block_cache.get(self.inode, index)))
+            .collect()
+    }
+
+    /// Fills the missing blocks of the range from the disk cache or the service,
+    /// caching everything that was downloaded
+    pub async fn fetch(&self, mut blocks: Vec<Option<Bytes>>) -> Result<Vec<Option<Bytes>>> {
+        let indices = BlockCache::block_range(self.range.start, self.range.end);
+
+        // Blocks of a committed version may be on disk from an earlier read
+        if let Some((disk_cache, (etag, last_modified))) =
+            self.disk_cache.as_ref().zip(self.version.as_ref())
+        {
+            let found: Vec<(usize, PathBuf)> = {
+                let mut disk_cache = disk_cache.lock().unwrap();
+                indices
+                    .clone()
+                    .zip(&blocks)
+                    .enumerate()
+                    .filter(|(_, (_, block))| block.is_none())
+                    .filter_map(|(slot, (index, _))| {
+                        let path = disk_cache.lookup(&self.name, etag, *last_modified, index)?;
+                        Some((slot, path))
+                    })
+                    .collect()
+            };
+            if !found.is_empty() {
+                // The files are read without holding either cache's lock
+                let loaded = tokio::task::spawn_blocking(move || {
+                    found
+                        .into_iter()
+                        .map(|(slot, path)| {
+                            let data = DiskCache::read_block(&path);
+                            (slot, path, data)
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .await?;
+                let mut unreadable = Vec::new();
+                {
+                    let mut block_cache = self.block_cache.lock().unwrap();
+                    for (slot, path, data) in loaded {
+                        match data {
+                            Ok(block) => {
+                                let index = indices.start + slot as u64;
+                                block_cache.insert_if_current(
+                                    self.inode,
+                                    self.generation,
+                                    index,
+                                    block.clone(),
+                                );
+                                blocks[slot] = Some(block);
+                            }
+                            // Reserved by a download whose write has not landed yet
+                            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                            Err(err) => {
+                                warn!("Failed to read cache file {}: {err}", path.display());
+                                unreadable.push(path);
+                            }
+                        }
+                    }
+                }
+                if !unreadable.is_empty() {
+                    let mut disk_cache = disk_cache.lock().unwrap();
+                    for path in unreadable {
+                        disk_cache.forget(&path);
+                    }
+                }
+            }
+        }
+
+        let mut position = 0;
+        while position < blocks.len() {
+            if blocks[position].is_some() {
+                position += 1;
+                continue;
+            }
+            let run_start = position;
+            while position < blocks.len() && blocks[position].is_none() {
+                position += 1;
+            }
+            let range = (indices.start + run_start as u64) * CACHE_BLOCK_SIZE
+                ..((indices.start + position as u64) * CACHE_BLOCK_SIZE).min(self.size);
+            let etag = self.version.as_ref().map(|(etag, _)| etag.as_str());
+            let data = match &self.pages {
+                // Sparse regions of page blobs are zero-filled locally instead of downloaded
+                Some(pages) => pages.read(&self.client, &self.name, range).await?,
+                None => {
+                    self.download
+                        .download(&self.client, &self.name, range, etag)
+                        .await?
+                }
+            };
+
+            {
+                let mut block_cache = self.block_cache.lock().unwrap();
+                for (slot, chunk) in
+                    (run_start..position).zip(data.chunks(CACHE_BLOCK_SIZE as usize))
+                {
+                    let block = data.slice_ref(chunk);
+                    let index = indices.start + slot as u64;
+                    block_cache.insert_if_current(
+                        self.inode,
+                        self.generation,
+                        index,
+                        block.clone(),
+                    );
+                    blocks[slot] = Some(block);
+                }
+            }
+            self.store_on_disk(
+                indices.start + run_start as u64,
+                &blocks[run_start..position],
+            );
+        }
+        Ok(blocks)
+    }
+
+    /// Writes downloaded blocks to the disk cache in the background, reserving
+    /// their room under the lock and writing the files outside of it
+    fn store_on_disk(&self, first_index: u64, blocks: &[Option<Bytes>]) {
+        let Some((disk_cache, (etag, last_modified))) =
+            self.disk_cache.as_ref().zip(self.version.as_ref())
+        else {
+            return;
+        };
+        let pending: Vec<(PendingBlock, Bytes)> = {
+            let mut disk_cache = disk_cache.lock().unwrap();
+            (first_index..)
+                .zip(blocks.iter().flatten())
+                .filter_map(|(index, block)| {
+                    let size = block.len() as u64;
+                    let pending =
+                        disk_cache.reserve(&self.name, etag, *last_modified, index, size)?;
+                    Some((pending, block.clone()))
+                })
+                .collect()
+        };
+        if pending.is_empty() {
+            return;
+        }
+        let disk_cache = disk_cache.clone();
+        let name = self.name.clone();
+        tokio::task::spawn_blocking(move || {
+            for (pending, block) in pending {
+                let path = pending.path.clone();
+                if let Err(err) = pending.write(&block) {
+                    warn!("Failed to cache {} of blob {name}: {err}", path.display());
+                    disk_cache.lock().unwrap().forget(&path);
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,731 @@
+use anyhow::{Context, Result};
+use azure_core::Bytes;
+use azure_core::http::RequestContent;
+use azure_core::http::headers::CONTENT_TYPE;
+use azure_core::time::{OffsetDateTime, to_rfc3339};
+use azure_storage_blob::BlobContainerClient;
+use azure_storage_blob::models::{
+    BlobClientDownloadOptions, BlobClientGetPropertiesResultHeaders,
+    BlockBlobClientCommitBlockListOptions, BlockBlobClientCommitBlockListResultHeaders,
+    BlockListType, BlockLookupList,
+};
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::time::SystemTime;
+
+use crate::listing::MTIME_METADATA;
+use crate::rest_client::BlobRestClient;
+
+/// Size of the blocks staged with Put Block while writing a blob
+pub const BLOCK_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Length of the block ids generated for blobs that have no block list yet
+const DEFAULT_ID_LENGTH: usize = 16;
+
+#[derive(Debug, Clone)]
+enum BlockData {
+    /// Part of the committed block list, referenced by id
+    Committed(Vec<u8>),
+    /// Unchanged range of a blob that was uploaded without a block list
+    Source,
+    /// Staged with Put Block, not yet committed
+    Staged(Vec<u8>),
+    /// Modified in memory, staged on the next commit
+    Dirty(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+struct Block {
+    offset: u64,
+    size: u64,
+    data: BlockData,
+}
+
+impl Block {
+    fn end(&self) -> u64 {
+        self.offset + self.size
+    }
+}
+
+/// Properties of an existing blob that Put Block List would otherwise reset
+#[derive(Debug, Clone, Default)]
+struct BlobProperties {
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    content_language: Option<String>,
+    cache_control: Option<String>,
+    content_disposition: Option<String>,
+    metadata: HashMap<String, String>,
+}
+
+/// Modified blocks laid over a read of the committed blob, for reads that
+/// modified blocks don't cover alone
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    pub range: Range<u64>,           // Range being read
+    pub committed: Range<u64>,       // Part of it downloaded, covering the blocks the service has
+    pub committed_size: u64,         // Size of the blob on the service
+    pub modified: Vec<(u64, Bytes)>, // Offset and data of the modified parts of the range
+}
+
+impl Overlay {
+    /// Lays the modified parts over the data downloaded for `committed`
+    pub fn apply(&self, committed: &[u8]) -> Bytes {
+        let mut data = vec![0; (self.range.end - self.range.start) as usize];
+        let start = (self.committed.start - self.range.start) as usize;
+        let length = committed.len().min(data.len() - start);
+        data[start..start + length].copy_from_slice(&committed[..length]);
+        for (offset, bytes) in &self.modified {
+            let start = (offset - self.range.start) as usize;
+            data[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        Bytes::from(data)
+    }
+}
+
+/// Block list of a blob that is being written.
+///
+/// Existing blocks stay referenced by id, so only blocks touched by a write
+/// are downloaded, patched and re-staged when the block list is committed.
+#[derive(Debug, Clone, Default)]
+pub struct BlobUpload {
+    blocks: Vec<Block>,
+    id_length: usize,
+    used_ids: HashSet<Vec<u8>>,
+    next_id: u64,
+    dirty: bool,
+    etag: Option<String>,
+    committed_size: u64,                // Size of the blob as of the last commit
+    properties: Option<BlobProperties>, // Carried over to every commit of an existing blob
+}
+
+impl BlobUpload {
+    /// Upload state for a newly created blob, committed even if nothing is written
+    pub fn new() -> Self {
+        Self {
+            id_length: DEFAULT_ID_LENGTH,
+            dirty: true,
+            ..Default::default()
+        }
+    }
+
+    /// Upload state that empties an existing blob, keeping its properties
+    pub async fn empty_keeping_properties(
+        client: &BlobContainerClient,
+        name: &str,
+    ) -> Result<Self> {
+        let properties = Self::properties(client, name).await?;
+        Ok(Self {
+            properties: Some(properties),
+            ..Self::new()
+        })
+    }
+
+    /// Loads the committed block list and the properties of an existing blob
+    pub async fn open(client: &BlobContainerClient, name: &str, size: u64) -> Result<Self> {
+        let properties = Self::properties(client, name).await?;
+        let blob_client = client.blob_client(name.to_string());
+        let block_list = blob_client
+            .block_blob_client()
+            .get_block_list(BlockListType::Committed, None)
+            .await
+            .context(format!("Failed to get block list for blob: {name}"))?
+            .into_body()
+            .await?;
+        let committed = block_list
+            .committed_blocks
+            .unwrap_or_default()
+            .into_iter()
+            .map(|block| {
+                (
+                    block.name.unwrap_or_default(),
+                    block.size.unwrap_or(0) as u64,
+                )
+            })
+            .collect();
+
+        let mut upload = Self::from_block_list(committed, size);
+        upload.properties = Some(properties);
+        info!(
+            "Opened blob {name} for writing with {} blocks",
+            upload.blocks.len()
+        );
+        Ok(upload)
+    }
+
+    /// Reads the properties and metadata of an existing blob with Get Blob Properties
+    async fn properties(client: &BlobContainerClient, name: &str) -> Result<BlobProperties> {
+        let response = client
+            .blob_client(name.to_string())
+            .get_properties(None)
+            .await
+            .context(format!("Failed to get properties for blob: {name}"))?;
+        Ok(BlobProperties {
+            content_type: response.headers().get_optional_string(&CONTENT_TYPE),
+            content_encoding: response.content_encoding()?,
+            content_language: response.content_language()?,
+            cache_control: response.cache_control()?,
+            content_disposition: response.content_disposition()?,
+            metadata: response.metadata()?,
+        })
+    }
+
+    /// Upload state from the ids and sizes of the committed blocks of a blob
+    fn from_block_list(committed: Vec<(Vec<u8>, u64)>, size: u64) -> Self {
+        let mut upload = Self {
+            id_length: DEFAULT_ID_LENGTH,
+            ..Default::default()
+        };
+        let mut offset = 0;
+        for (id, block_size) in committed {
+            upload.id_length = id.len();
+            upload.used_ids.insert(id.clone());
+            upload.blocks.push(Block {
+                offset,
+                size: block_size,
+                data: BlockData::Committed(id),
+            });
+            offset += block_size;
+        }
+
+        // Blobs written with a single Put Blob have no blocks to reference
+        if upload.blocks.is_empty() {
+            while offset < size {
+                let block_size = BLOCK_SIZE.min(size - offset);
+                upload.blocks.push(Block {
+                    offset,
+                    size: block_size,
+                    data: BlockData::Source,
+                });
+                offset += block_size;
+            }
+        }
+        upload.committed_size = offset;
+        upload
+    }
+
+    /// Logical size of the blob including uncommitted writes
+    pub fn size(&self) -> u64 {
+        self.blocks.last().map(Block::end).unwrap_or(0)
+    }
+
+    /// Returns true if there are writes that have not been committed
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// ETag returned by the last commit
+    pub fn etag(&self) -> Option<String> {
+        self.etag.clone()
+    }
+
+    /// Staged blocks can't be downloaded, so they must be committed before a patch
+    fn needs_commit(&self, range: Range<u64>) -> bool {
+        self.blocks.iter().any(|block| {
+            matches!(block.data, BlockData::Staged(_))
+                && block.offset < range.end
+                && range.start < block.end()
+        })
+    }
+
+    /// Serves a read from modified blocks if they cover the whole range
+    pub fn read_local(&self, range: Range<u64>) -> Option<Bytes> {
+        let mut data = Vec::with_capacity((range.end - range.start) as usize);
+        let mut position = range.start;
+        for block in &self.blocks {
+            if block.end() <= position || position >= range.end {
+                continue;
+            }
+            let BlockData::Dirty(bytes) = &block.data else {
+                return None;
+            };
+            let start = (position - block.offset) as usize;
+            let end = (range.end.min(block.end()) - block.offset) as usize;
+            data.extend_from_slice(&bytes[start..end]);
+            position = block.offset + end as u64;
+        }
+        (position >= range.end).then(|| Bytes::from(data))
+    }
+
+    fn next_block_id(&mut self) -> Result<Vec<u8>> {
+        // Block ids must all have the same length within a blob
+        loop {
+            let id = format!("{:0width$}", self.next_id, width = self.id_length).into_bytes();
+            self.next_id += 1;
+            if id.len() > self.id_length {
+                anyhow::bail!("Ran out of {} byte block ids", self.id_length);
+            }
+            if self.used_ids.insert(id.clone()) {
+                return Ok(id);
+            }
+        }
+    }
+
+    /// Splits a read into the part to download and the modified blocks to lay over it.
+    ///
+    /// Returns None if part of the range was staged but not committed, since
+    /// staged blocks can't be downloaded.
+    pub fn overlay(&self, range: Range<u64>) -> Option<Overlay> {
+        if self.needs_commit(range.clone()) {
+            return None;
+        }
+        let mut committed: Option<Range<u64>> = None;
+        let mut modified = Vec::new();
+        for block in self
+            .blocks
+            .iter()
+            .filter(|block| block.offset < range.end && range.start < block.end())
+        {
+            let start = range.start.max(block.offset);
+            let end = range.end.min(block.end());
+            match &block.data {
+                BlockData::Dirty(bytes) => {
+                    let data =
+                        &bytes[(start - block.offset) as usize..(end - block.offset) as usize];
+                    modified.push((start, Bytes::copy_from_slice(data)));
+                }
+                _ => {
+                    committed = Some(match committed {
+                        Some(committed) => committed.start..end,
+                        None => start..end,
+                    });
+                }
+            }
+        }
+        Some(Overlay {
+            committed: committed.unwrap_or(range.start..range.start),
+            range,
+            committed_size: self.committed_size,
+            modified,
+        })
+    }
+
+    /// Downloads a block so it can be patched in memory
+    async fn load_block(client: &BlobContainerClient, name: &str, block: &mut Block) -> Result<()> {
+        if matches!(block.data, BlockData::Dirty(_)) {
+            return Ok(());
+        }
+        let bytes = if block.size == 0 {
+            Vec::new()
+        } else {
+            let options = BlobClientDownloadOptions {
+                range: Some(format!("bytes={}-{}", block.offset, block.end() - 1)),
+                ..Default::default()
+            };
+            client
+                .blob_client(name.to_string())
+                .download(Some(options))
+                .await
+                .context(format!("Failed to download block of blob: {name}"))?
+                .into_raw_body()
+                .collect()
+                .await?
+                .to_vec()
+        };
+        block.data = BlockData::Dirty(bytes);
+        Ok(())
+    }
+
+    /// Writes data at the given offset, downloading and patching only the affected blocks.
+    ///
+    /// Appends are staged as soon as a block fills up so new files stream to the service.
+    pub async fn write(
+        &mut self,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+        name: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let appending = offset >= self.size();
+        let end = offset + data.len() as u64;
+        if self.needs_commit(offset..end) {
+            self.commit(client, rest_client, name).await?;
+        }
+
+        for block in self
+            .blocks
+            .iter_mut()
+            .filter(|block| block.offset < end && offset < block.end())
+        {
+            Self::load_block(client, name, block).await?;
+        }
+        self.apply_write(offset, data);
+
+        if appending {
+            let last = self.blocks.len().saturating_sub(1);
+            for index in 0..last {
+                if matches!(self.blocks[index].data, BlockData::Dirty(_))
+                    && self.blocks[index].size >= BLOCK_SIZE
+                {
+                    self.stage(client, rest_client, name, index).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Patches the blocks covered by a write, which must already be loaded, and
+    /// extends the blob past its end
+    fn apply_write(&mut self, offset: u64, data: &[u8]) {
+        let end = offset + data.len() as u64;
+
+        // Overwrite the parts of existing blocks covered by the write
+        for block in self
+            .blocks
+            .iter_mut()
+            .filter(|block| block.offset < end && offset < block.end())
+        {
+            let start = offset.max(block.offset);
+            let stop = end.min(block.end());
+            if let BlockData::Dirty(bytes) = &mut block.data {
+                bytes[(start - block.offset) as usize..(stop - block.offset) as usize]
+                    .copy_from_slice(&data[(start - offset) as usize..(stop - offset) as usize]);
+            }
+        }
+
+        // Extend the blob, filling any gap before the offset with zeros
+        let mut position = self.size();
+        while position < end {
+            let fill_tail = matches!(
+                self.blocks.last(),
+                Some(Block { data: BlockData::Dirty(_), size, .. }) if *size < BLOCK_SIZE
+            );
+            if !fill_tail {
+                self.blocks.push(Block {
+                    offset: position,
+                    size: 0,
+                    data: BlockData::Dirty(Vec::new()),
+                });
+            }
+            let tail = self.blocks.last_mut().expect("tail block");
+            let chunk_end = end.min(tail.offset + BLOCK_SIZE);
+            if let BlockData::Dirty(bytes) = &mut tail.data {
+                let data_start = position.max(offset).min(chunk_end);
+                bytes.resize((data_start - tail.offset) as usize, 0);
+                if data_start < chunk_end {
+                    bytes.extend_from_slice(
+                        &data[(data_start - offset) as usize..(chunk_end - offset) as usize],
+                    );
+                }
+            }
+            tail.size = chunk_end - tail.offset;
+            position = chunk_end;
+        }
+        self.dirty = true;
+    }
+
+    /// Shrinks the blob to `size` or extends it with zeros
+    pub async fn truncate(
+        &mut self,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+        name: &str,
+        size: u64,
+    ) -> Result<()> {
+        let current = self.size();
+        if size > current {
+            // Extend a block at a time so the zeros are staged like any append
+            let mut position = current;
+            while position < size {
+                let chunk = BLOCK_SIZE.min(size - position);
+                let zeros = vec![0; chunk as usize];
+                self.write(client, rest_client, name, position, &zeros)
+                    .await?;
+                position += chunk;
+            }
+            return Ok(());
+        }
+        if size == current {
+            return Ok(());
+        }
+
+        if self.needs_commit(size..size + 1) {
+            self.commit(client, rest_client, name).await?;
+        }
+        self.blocks.retain(|block| block.offset < size);
+        if let Some(last) = self.blocks.last_mut().filter(|block| block.end() > size) {
+            Self::load_block(client, name, last).await?;
+            if let BlockData::Dirty(bytes) = &mut last.data {
+                bytes.truncate((size - last.offset) as usize);
+            }
+            last.size = size - last.offset;
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Stages one block, server-side for unchanged ranges of a blob without a block list
+    async fn stage(
+        &mut self,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+        name: &str,
+        index: usize,
+    ) -> Result<()> {
+        let block_id = self.next_block_id()?;
+        let block = &mut self.blocks[index];
+        match std::mem::replace(&mut block.data, BlockData::Staged(block_id.clone())) {
+            BlockData::Dirty(bytes) => {
+                client
+                    .blob_client(name.to_string())
+                    .block_blob_client()
+                    .stage_block(&block_id, block.size, RequestContent::from(bytes), None)
+                    .await
+                    .context(format!("Failed to stage block for blob: {name}"))?;
+            }
+            BlockData::Source => {
+                rest_client
+                    .stage_block_from_url(name, &block_id, block.offset..block.end())
+                    .await?;
+            }
+            data => block.data = data,
+        }
+        Ok(())
+    }
+
+    /// Replaces the metadata the next commits carry over, once it was set on the blob
+    pub fn set_metadata(&mut self, metadata: HashMap<String, String>) {
+        self.properties.get_or_insert_default().metadata = metadata;
+    }
+
+    /// Options of Put Block List keeping the headers and metadata of an existing blob.
+    ///
+    /// A modification time kept in metadata moves forward to `committed_at`,
+    /// so listings don't report the time from before the write.
+    fn commit_options(
+        &self,
+        committed_at: SystemTime,
+    ) -> Option<BlockBlobClientCommitBlockListOptions<'static>> {
+        let properties = self.properties.as_ref()?;
+        let mut metadata = properties.metadata.clone();
+        if let Some(mtime) = metadata
+            .iter_mut()
+            .find(|(key, _)| key.eq_ignore_ascii_case(MTIME_METADATA))
+            .map(|(_, value)| value)
+        {
+            *mtime = to_rfc3339(&OffsetDateTime::from(committed_at));
+        }
+        Some(BlockBlobClientCommitBlockListOptions {
+            blob_content_type: properties.content_type.clone(),
+            blob_content_encoding: properties.content_encoding.clone(),
+            blob_content_language: properties.content_language.clone(),
+            blob_cache_control: properties.cache_control.clone(),
+            blob_content_disposition: properties.content_disposition.clone(),
+            metadata: (!metadata.is_empty()).then_some(metadata),
+            ..Default::default()
+        })
+    }
+
+    /// Stages every modified block and commits the block list
+    pub async fn commit(
+        &mut self,
+        client: &BlobContainerClient,
+        rest_client: &BlobRestClient,
+        name: &str,
+    ) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        for index in 0..self.blocks.len() {
+            if matches!(
+                self.blocks[index].data,
+                BlockData::Dirty(_) | BlockData::Source
+            ) {
+                self.stage(client, rest_client, name, index).await?;
+            }
+        }
+
+        let block_ids: Vec<Vec<u8>> = self
+            .blocks
+            .iter()
+            .filter_map(|block| match &block.data {
+                BlockData::Committed(id) | BlockData::Staged(id) => Some(id.clone()),
+                _ => None,
+            })
+            .collect();
+        let block_list = BlockLookupList {
+            committed: Some(Vec::new()),
+            latest: Some(block_ids),
+            uncommitted: Some(Vec::new()),
+        };
+        let response = client
+            .blob_client(name.to_string())
+            .block_blob_client()
+            .commit_block_list(
+                RequestContent::try_from(block_list)?,
+                self.commit_options(SystemTime::now()),
+            )
+            .await
+            .context(format!("Failed to commit block list for blob: {name}"))?;
+        self.etag = response.etag()?;
+
+        for block in &mut self.blocks {
+            if let BlockData::Staged(id) = &block.data {
+                block.data = BlockData::Committed(id.clone());
+            }
+        }
+        self.dirty = false;
+        self.committed_size = self.size();
+        info!(
+            "Committed {} blocks ({} bytes) for blob: {name}",
+            self.blocks.len(),
+            self.size()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn sizes(upload: &BlobUpload) -> Vec<u64> {
+        upload.blocks.iter().map(|block| block.size).collect()
+    }
+
+    #[test]
+    fn blobs_without_block_list_are_split_into_source_blocks() {
+        let upload = BlobUpload::from_block_list(Vec::new(), 9 * MIB);
+        assert_eq!(sizes(&upload), vec![4 * MIB, 4 * MIB, MIB]);
+        assert!(
+            upload
+                .blocks
+                .iter()
+                .all(|block| matches!(block.data, BlockData::Source))
+        );
+        assert_eq!(upload.blocks[2].offset, 8 * MIB);
+        assert_eq!(upload.size(), 9 * MIB);
+        assert!(!upload.is_dirty());
+    }
+
+    #[test]
+    fn committed_blocks_are_referenced_by_id() {
+        let committed = vec![(b"aaaa".to_vec(), 100), (b"bbbb".to_vec(), 50)];
+        let upload = BlobUpload::from_block_list(committed, 150);
+        assert_eq!(sizes(&upload), vec![100, 50]);
+        assert_eq!(upload.blocks[1].offset, 100);
+        assert!(matches!(&upload.blocks[0].data, BlockData::Committed(id) if id == b"aaaa"));
+        assert_eq!(upload.id_length, 4);
+    }
+
+    #[test]
+    fn block_ids_keep_their_length_and_skip_used_ones() {
+        let committed = vec![(b"0000".to_vec(), 1), (b"0001".to_vec(), 1)];
+        let mut upload = BlobUpload::from_block_list(committed, 2);
+        assert_eq!(upload.next_block_id().unwrap(), b"0002");
+        assert_eq!(upload.next_block_id().unwrap(), b"0003");
+
+        let mut upload = BlobUpload::from_block_list(vec![(b"9".to_vec(), 1)], 1);
+        assert_eq!(upload.next_block_id().unwrap(), b"0");
+        upload.next_id = 9;
+        assert!(upload.next_block_id().is_err());
+    }
+
+    #[test]
+    fn appends_fill_blocks_up_to_the_block_size() {
+        let mut upload = BlobUpload::new();
+        upload.apply_write(0, &vec![1; (5 * MIB) as usize]);
+        assert_eq!(sizes(&upload), vec![4 * MIB, MIB]);
+
+        upload.apply_write(5 * MIB, &vec![2; (3 * MIB) as usize + 1]);
+        assert_eq!(sizes(&upload), vec![4 * MIB, 4 * MIB, 1]);
+        assert_eq!(upload.size(), 8 * MIB + 1);
+        let data = upload.read_local(5 * MIB - 1..5 * MIB + 1).unwrap();
+        assert_eq!(&data[..], &[1, 2]);
+    }
+
+    #[test]
+    fn writes_patch_loaded_blocks() {
+        let mut upload = BlobUpload::new();
+        upload.apply_write(0, b"hello world");
+        upload.apply_write(6, b"there");
+        assert_eq!(&upload.read_local(0..11).unwrap()[..], b"hello there");
+        assert_eq!(upload.size(), 11);
+    }
+
+    #[test]
+    fn writes_past_the_end_fill_the_gap_with_zeros() {
+        let mut upload = BlobUpload::new();
+        upload.apply_write(0, b"ab");
+        upload.apply_write(5, b"cd");
+        assert_eq!(upload.size(), 7);
+        assert_eq!(&upload.read_local(0..7).unwrap()[..], b"ab\0\0\0cd");
+    }
+
+    #[test]
+    fn commits_keep_the_properties_of_existing_blobs() {
+        assert!(
+            BlobUpload::new()
+                .commit_options(SystemTime::now())
+                .is_none()
+        );
+
+        let mut upload = BlobUpload::from_block_list(Vec::new(), 0);
+        upload.properties = Some(BlobProperties {
+            content_type: Some("video/mp4".to_string()),
+            cache_control: Some("max-age=60".to_string()),
+            metadata: HashMap::from([
+                ("owner".to_string(), "ffmpeg".to_string()),
+                ("Mtime".to_string(), "2020-01-01T00:00:00Z".to_string()),
+            ]),
+            ..Default::default()
+        });
+        let committed_at = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let options = upload.commit_options(committed_at).unwrap();
+        assert_eq!(options.blob_content_type.as_deref(), Some("video/mp4"));
+        assert_eq!(options.blob_cache_control.as_deref(), Some("max-age=60"));
+        assert!(options.blob_content_encoding.is_none());
+        let metadata = options.metadata.unwrap();
+        assert_eq!(metadata["owner"], "ffmpeg");
+        assert_eq!(
+            metadata["Mtime"],
+            to_rfc3339(&OffsetDateTime::from(committed_at))
+        );
+    }
+
+    #[test]
+    fn reads_lay_modified_blocks_over_the_committed_blob() {
+        let committed = vec![(b"aaaa".to_vec(), 4), (b"bbbb".to_vec(), 4)];
+        let mut upload = BlobUpload::from_block_list(committed, 8);
+        upload.apply_write(8, b"new");
+
+        let overlay = upload.overlay(2..11).unwrap();
+        assert_eq!(overlay.committed, 2..8);
+        assert_eq!(overlay.committed_size, 8);
+        assert_eq!(overlay.modified, vec![(8, Bytes::from_static(b"new"))]);
+        assert_eq!(&overlay.apply(b"AABBBB")[..], b"AABBBBnew");
+
+        // Blocks patched in memory replace what was downloaded for them
+        let mut upload = BlobUpload::from_block_list(vec![(b"aaaa".to_vec(), 4)], 4);
+        upload.blocks.push(Block {
+            offset: 4,
+            size: 4,
+            data: BlockData::Committed(b"bbbb".to_vec()),
+        });
+        upload.blocks[0].data = BlockData::Dirty(b"xyzw".to_vec());
+        upload.dirty = true;
+        let overlay = upload.overlay(2..6).unwrap();
+        assert_eq!(overlay.committed, 4..6);
+        assert_eq!(&overlay.apply(b"BB")[..], b"zwBB");
+    }
+
+    #[test]
+    fn staged_blocks_need_a_commit_before_reading() {
+        let mut upload = BlobUpload::new();
+        upload.apply_write(0, b"abcd");
+        upload.blocks[0].data = BlockData::Staged(b"0000".to_vec());
+        assert!(upload.overlay(0..4).is_none());
+    }
+
+    #[test]
+    fn local_reads_need_modified_blocks() {
+        let mut upload = BlobUpload::from_block_list(vec![(b"aaaa".to_vec(), 10)], 10);
+        upload.apply_write(10, b"new");
+        assert_eq!(&upload.read_local(10..13).unwrap()[..], b"new");
+        assert!(upload.read_local(8..12).is_none());
+    }
+}
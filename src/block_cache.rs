@@ -0,0 +1,209 @@
+use azure_core::Bytes;
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+
+/// Reads are cached in aligned blocks of this size
+pub const CACHE_BLOCK_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug)]
+struct CachedBlock {
+    data: Bytes,
+    last_used: u64,
+}
+
+/// Container-wide read cache of fixed-size blocks, evicted least recently used first
+#[derive(Debug)]
+pub struct BlockCache {
+    capacity: u64,
+    used: u64,
+    tick: u64,
+    blocks: HashMap<(u64, u64), CachedBlock>, // (inode, block index) -> block
+    lru: BTreeMap<u64, (u64, u64)>,           // last_used -> (inode, block index)
+    generations: HashMap<u64, u64>,           // Bumped whenever an inode is invalidated
+}
+
+impl BlockCache {
+    /// Creates a cache holding at most `capacity` bytes
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            capacity,
+            used: 0,
+            tick: 0,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            generations: HashMap::new(),
+        }
+    }
+
+    /// Range of block indices covering `start..end`
+    pub fn block_range(start: u64, end: u64) -> std::ops::Range<u64> {
+        start / CACHE_BLOCK_SIZE..end.div_ceil(CACHE_BLOCK_SIZE)
+    }
+
+    /// Returns a cached block and marks it as most recently used
+    pub fn get(&mut self, inode: u64, index: u64) -> Option<Bytes> {
+        self.tick += 1;
+        let block = self.blocks.get_mut(&(inode, index))?;
+        self.lru.remove(&block.last_used);
+        self.lru.insert(self.tick, (inode, index));
+        block.last_used = self.tick;
+        Some(block.data.clone())
+    }
+
+    /// Returns true if a block is cached, without marking it as used
+    pub fn contains(&self, inode: u64, index: u64) -> bool {
+        self.blocks.contains_key(&(inode, index))
+    }
+
+    /// Current generation of an inode's blocks, to detect invalidation during a download
+    pub fn generation(&self, inode: u64) -> u64 {
+        self.generations.get(&inode).copied().unwrap_or(0)
+    }
+
+    /// Caches a block downloaded in the background unless the inode changed meanwhile
+    pub fn insert_if_current(&mut self, inode: u64, generation: u64, index: u64, data: Bytes) {
+        if self.generation(inode) == generation {
+            self.insert(inode, index, data);
+        }
+    }
+
+    /// Caches a block, evicting the least recently used blocks to stay within budget
+    pub fn insert(&mut self, inode: u64, index: u64, data: Bytes) {
+        let size = data.len() as u64;
+        if size > self.capacity {
+            return;
+        }
+        self.remove(inode, index);
+        while self.used + size > self.capacity {
+            let Some((_, (inode, index))) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(block) = self.blocks.remove(&(inode, index)) {
+                self.used -= block.data.len() as u64;
+            }
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, (inode, index));
+        self.blocks.insert(
+            (inode, index),
+            CachedBlock {
+                data,
+                last_used: self.tick,
+            },
+        );
+        self.used += size;
+    }
+
+    fn remove(&mut self, inode: u64, index: u64) {
+        if let Some(block) = self.blocks.remove(&(inode, index)) {
+            self.lru.remove(&block.last_used);
+            self.used -= block.data.len() as u64;
+        }
+    }
+
+    /// Drops every cached block of an inode after its content changed
+    pub fn invalidate(&mut self, inode: u64) {
+        *self.generations.entry(inode).or_default() += 1;
+        let indices: Vec<u64> = self
+            .blocks
+            .keys()
+            .filter(|(cached, _)| *cached == inode)
+            .map(|(_, index)| *index)
+            .collect();
+        if !indices.is_empty() {
+            info!("Dropping {} cached blocks of inode {inode}", indices.len());
+        }
+        for index in indices {
+            self.remove(inode, index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(size: usize) -> Bytes {
+        Bytes::from(vec![0; size])
+    }
+
+    #[test]
+    fn evicts_least_recently_used_at_budget() {
+        let mut cache = BlockCache::new(300);
+        cache.insert(1, 0, block(100));
+        cache.insert(1, 1, block(100));
+        cache.insert(2, 0, block(100));
+        // Reading block 0 makes block 1 the least recently used
+        assert!(cache.get(1, 0).is_some());
+
+        cache.insert(2, 1, block(100));
+        assert!(cache.contains(1, 0));
+        assert!(!cache.contains(1, 1));
+        assert!(cache.contains(2, 0));
+        assert!(cache.contains(2, 1));
+        assert_eq!(cache.used, 300);
+    }
+
+    #[test]
+    fn evicts_as_many_blocks_as_needed() {
+        let mut cache = BlockCache::new(300);
+        for index in 0..3 {
+            cache.insert(1, index, block(100));
+        }
+        cache.insert(2, 0, block(250));
+        assert_eq!(cache.blocks.len(), 1);
+        assert!(cache.contains(2, 0));
+        assert_eq!(cache.used, 250);
+    }
+
+    #[test]
+    fn skips_blocks_above_budget() {
+        let mut cache = BlockCache::new(100);
+        cache.insert(1, 0, block(50));
+        cache.insert(1, 1, block(101));
+        assert!(cache.contains(1, 0));
+        assert!(!cache.contains(1, 1));
+    }
+
+    #[test]
+    fn replacing_a_block_keeps_the_accounting() {
+        let mut cache = BlockCache::new(300);
+        cache.insert(1, 0, block(100));
+        cache.insert(1, 0, block(200));
+        assert_eq!(cache.used, 200);
+        assert_eq!(cache.lru.len(), 1);
+    }
+
+    #[test]
+    fn invalidation_drops_blocks_and_rejects_older_downloads() {
+        let mut cache = BlockCache::new(1000);
+        cache.insert(1, 0, block(100));
+        cache.insert(2, 0, block(100));
+        let generation = cache.generation(1);
+
+        cache.invalidate(1);
+        assert!(!cache.contains(1, 0));
+        assert!(cache.contains(2, 0));
+        assert_eq!(cache.generation(1), generation + 1);
+
+        // A download started before the invalidation must not be cached
+        cache.insert_if_current(1, generation, 1, block(100));
+        assert!(!cache.contains(1, 1));
+        cache.insert_if_current(1, generation + 1, 1, block(100));
+        assert!(cache.contains(1, 1));
+    }
+
+    #[test]
+    fn block_range_covers_partial_blocks() {
+        assert_eq!(BlockCache::block_range(0, 1), 0..1);
+        assert_eq!(
+            BlockCache::block_range(CACHE_BLOCK_SIZE - 1, CACHE_BLOCK_SIZE + 1),
+            0..2
+        );
+        assert_eq!(
+            BlockCache::block_range(CACHE_BLOCK_SIZE, 2 * CACHE_BLOCK_SIZE),
+            1..2
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use anyhow::{Context, Result};
+use azure_core::Bytes;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::hash::hash_name;
+
+/// Prefix of the files holding cached blocks, followed by the block index
+const BLOCK_PREFIX: &str = "block-";
+
+/// Extension of block files while they are written
+const TEMPORARY_EXTENSION: &str = "tmp";
+
+/// Subdirectory of the cache directory holding the blocks, so nothing else in it is touched
+const BLOCKS_DIR: &str = "azure-blob-fuse-blocks";
+
+/// Cached block files
+#[derive(Debug)]
+struct CachedFile {
+    size: u64,
+    last_used: SystemTime,
+}
+
+/// Persistent cache of downloaded blocks, one directory per blob version.
+///
+/// Blocks live in a subdirectory of the configured directory, and only
+/// entries the cache created are ever removed from it.
+///
+/// Blocks are only served while the blob's ETag and Last-Modified match the
+/// version they were downloaded from. The least recently used block files are
+/// evicted to keep the cache below its maximum size.
+///
+/// The index is kept in memory so lookups never touch the disk. Reading and
+/// writing block files is left to the caller, outside the cache's lock.
+#[derive(Debug)]
+pub struct DiskCache {
+    root: PathBuf,
+    max_size: u64,
+    used: u64,
+    files: HashMap<PathBuf, CachedFile>,
+    // Cached version of every blob, by hash of the blob name
+    versions: HashMap<u64, u64>,
+}
+
+/// A block reserved in the cache, to be written outside its lock
+#[derive(Debug)]
+pub struct PendingBlock {
+    dir: PathBuf,
+    pub path: PathBuf,
+    // Files and directories evicted to make room, removed before writing
+    garbage: Vec<PathBuf>,
+}
+
+impl PendingBlock {
+    /// Writes the block, first removing what was evicted for it
+    pub fn write(self, data: &[u8]) -> io::Result<()> {
+        DiskCache::remove_paths(&self.garbage);
+        fs::create_dir_all(&self.dir)?;
+        // Written under a temporary name so a crash never leaves a partial block
+        let temporary = self.path.with_extension(TEMPORARY_EXTENSION);
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+/// Identifies the version of a blob whose blocks may be cached
+fn version_of(etag: &str, last_modified: SystemTime) -> u64 {
+    let modified = last_modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    hash_name(&format!("{etag}\n{modified}\n"))
+}
+
+/// Splits a "{name hash}-{version hash}" directory name
+fn parse_dir_name(name: &str) -> Option<(u64, u64)> {
+    let (blob, version) = name.split_once('-')?;
+    Some((
+        u64::from_str_radix(blob, 16).ok()?,
+        u64::from_str_radix(version, 16).ok()?,
+    ))
+}
+
+/// Parses the index of a "block-{index}" file name
+fn parse_block_name(name: &str) -> Option<u64> {
+    name.strip_prefix(BLOCK_PREFIX)?.parse().ok()
+}
+
+impl DiskCache {
+    /// Opens the cache in a directory, dropping incomplete entries and evicting down to `max_size`
+    pub fn open(dir: &Path, max_size: u64) -> Result<Self> {
+        let root = dir.join(BLOCKS_DIR);
+        fs::create_dir_all(&root).context(format!(
+            "Failed to create cache directory: {}",
+            root.display()
+        ))?;
+        let mut cache = Self {
+            root,
+            max_size,
+            used: 0,
+            files: HashMap::new(),
+            versions: HashMap::new(),
+        };
+        cache.scan()?;
+        let garbage = cache.evict(0);
+        Self::remove_paths(&garbage);
+        info!(
+            "Disk cache at {} holds {} blocks ({} of {} bytes)",
+            cache.root.display(),
+            cache.files.len(),
+            cache.used,
+            cache.max_size
+        );
+        Ok(cache)
+    }
+
+    /// Indexes the block files left by a previous mount.
+    ///
+    /// Other versions of a blob and blocks whose write was interrupted are
+    /// removed. Anything the cache doesn't recognize is left alone.
+    fn scan(&mut self) -> Result<()> {
+        for dir in fs::read_dir(&self.root)? {
+            let dir = dir?;
+            let path = dir.path();
+            let parsed = parse_dir_name(&dir.file_name().to_string_lossy());
+            let Some((blob, version)) = parsed.filter(|_| path.is_dir()) else {
+                warn!("Ignoring unknown cache entry {}", path.display());
+                continue;
+            };
+            // Only one version of a blob is kept
+            if self.versions.contains_key(&blob) {
+                Self::remove_path(&path);
+                continue;
+            }
+            self.versions.insert(blob, version);
+            for file in fs::read_dir(&path)? {
+                let file = file?;
+                let path = file.path();
+                let metadata = file.metadata()?;
+                let name = file.file_name().to_string_lossy().to_string();
+                if parse_block_name(&name).is_some() && metadata.is_file() {
+                    self.used += metadata.len();
+                    self.files.insert(
+                        path,
+                        CachedFile {
+                            size: metadata.len(),
+                            last_used: metadata.modified().unwrap_or(UNIX_EPOCH),
+                        },
+                    );
+                } else if name
+                    .strip_suffix(&format!(".{TEMPORARY_EXTENSION}"))
+                    .and_then(parse_block_name)
+                    .is_some()
+                {
+                    // Temporary file of an interrupted write
+                    Self::remove_path(&path);
+                } else {
+                    warn!("Ignoring unknown cache entry {}", path.display());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_path(path: &Path) {
+        let result = if path.is_dir() {
+            fs::remove_dir_all(path)
+        } else {
+            fs::remove_file(path)
+        };
+        match result {
+            Ok(()) => {}
+            // Already gone, e.g. evicted twice before the first removal ran
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Failed to remove cache entry {}: {err}", path.display()),
+        }
+    }
+
+    /// Removes files and directories dropped from the cache, best effort
+    pub fn remove_paths(paths: &[PathBuf]) {
+        for path in paths {
+            Self::remove_path(path);
+        }
+    }
+
+    fn version_dir(&self, blob: u64, version: u64) -> PathBuf {
+        self.root.join(format!("{blob:016x}-{version:016x}"))
+    }
+
+    /// Drops the cached blocks of a blob unless they belong to the given version.
+    ///
+    /// Returns the paths to remove from disk.
+    #[must_use]
+    pub fn validate(
+        &mut self,
+        name: &str,
+        etag: Option<&str>,
+        last_modified: SystemTime,
+    ) -> Vec<PathBuf> {
+        let current = etag.map(|etag| version_of(etag, last_modified));
+        match self.versions.get(&hash_name(name)) {
+            None => Vec::new(),
+            Some(version) if Some(*version) == current => Vec::new(),
+            Some(_) => {
+                info!("Cached blocks of blob {name} are out of date");
+                self.invalidate(name)
+            }
+        }
+    }
+
+    /// Removes every cached block of a blob, returning the paths to remove from disk
+    #[must_use]
+    pub fn invalidate(&mut self, name: &str) -> Vec<PathBuf> {
+        self.drop_blob(hash_name(name)).into_iter().collect()
+    }
+
+    fn drop_blob(&mut self, blob: u64) -> Option<PathBuf> {
+        let version = self.versions.remove(&blob)?;
+        let dir = self.version_dir(blob, version);
+        let removed: Vec<PathBuf> = self
+            .files
+            .keys()
+            .filter(|path| path.starts_with(&dir))
+            .cloned()
+            .collect();
+        for path in removed {
+            if let Some(file) = self.files.remove(&path) {
+                self.used -= file.size;
+            }
+        }
+        Some(dir)
+    }
+
+    /// Finds a cached block of the given version of the blob, to read with [`Self::read_block`]
+    pub fn lookup(
+        &mut self,
+        name: &str,
+        etag: &str,
+        last_modified: SystemTime,
+        index: u64,
+    ) -> Option<PathBuf> {
+        let blob = hash_name(name);
+        let version = version_of(etag, last_modified);
+        if self.versions.get(&blob) != Some(&version) {
+            return None;
+        }
+        let path = self
+            .version_dir(blob, version)
+            .join(format!("{BLOCK_PREFIX}{index}"));
+        let file = self.files.get_mut(&path)?;
+        file.last_used = SystemTime::now();
+        Some(path)
+    }
+
+    /// Reads a block file found by [`Self::lookup`]
+    pub fn read_block(path: &Path) -> io::Result<Bytes> {
+        let data = fs::read(path)?;
+        // The modification time keeps the LRU order across mounts
+        if let Err(err) = fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|handle| handle.set_modified(SystemTime::now()))
+        {
+            warn!("Failed to touch cache file {}: {err}", path.display());
+        }
+        Ok(Bytes::from(data))
+    }
+
+    /// Drops a block file that could not be read or written
+    pub fn forget(&mut self, path: &Path) {
+        if let Some(file) = self.files.remove(path) {
+            self.used -= file.size;
+        }
+    }
+
+    /// Reserves room for a downloaded block, evicting older blocks to stay within
+    /// the maximum size. The block is written with [`PendingBlock::write`].
+    pub fn reserve(
+        &mut self,
+        name: &str,
+        etag: &str,
+        last_modified: SystemTime,
+        index: u64,
+        size: u64,
+    ) -> Option<PendingBlock> {
+        if size > self.max_size {
+            return None;
+        }
+        let blob = hash_name(name);
+        let version = version_of(etag, last_modified);
+        let mut garbage = Vec::new();
+        if self.versions.get(&blob) != Some(&version) {
+            garbage.extend(self.drop_blob(blob));
+            self.versions.insert(blob, version);
+        }
+
+        let dir = self.version_dir(blob, version);
+        let path = dir.join(format!("{BLOCK_PREFIX}{index}"));
+        self.forget(&path);
+        garbage.extend(self.evict(size));
+        self.used += size;
+        self.files.insert(
+            path.clone(),
+            CachedFile {
+                size,
+                last_used: SystemTime::now(),
+            },
+        );
+        Some(PendingBlock { dir, path, garbage })
+    }
+
+    /// Drops the least recently used blocks until `needed` more bytes fit,
+    /// returning their paths
+    fn evict(&mut self, needed: u64) -> Vec<PathBuf> {
+        let mut evicted = Vec::new();
+        if self.used + needed <= self.max_size {
+            return evicted;
+        }
+        let by_age: BTreeMap<(SystemTime, PathBuf), u64> = self
+            .files
+            .iter()
+            .map(|(path, file)| ((file.last_used, path.clone()), file.size))
+            .collect();
+        for ((_, path), size) in by_age {
+            if self.used + needed <= self.max_size {
+                break;
+            }
+            self.files.remove(&path);
+            self.used -= size;
+            evicted.push(path);
+        }
+        evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Cache directory of its own, removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new(test: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("disk-cache-{test}-{}", std::process::id())))
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_700_000_000)
+    }
+
+    /// Reserves a block and writes it, like a download does
+    fn store(cache: &mut DiskCache, name: &str, etag: &str, index: u64, size: usize) -> PathBuf {
+        let pending = cache
+            .reserve(name, etag, modified(), index, size as u64)
+            .unwrap();
+        let path = pending.path.clone();
+        pending.write(&vec![7; size]).unwrap();
+        path
+    }
+
+    #[test]
+    fn reserved_blocks_are_found_for_their_version_only() {
+        let dir = TestDir::new("lookup");
+        let mut cache = DiskCache::open(&dir.0, 1024).unwrap();
+        let path = store(&mut cache, "a", "etag", 3, 10);
+
+        assert_eq!(cache.lookup("a", "etag", modified(), 3), Some(path.clone()));
+        assert_eq!(&DiskCache::read_block(&path).unwrap()[..], &[7; 10]);
+        assert!(cache.lookup("a", "etag", modified(), 4).is_none());
+        assert!(cache.lookup("a", "other", modified(), 3).is_none());
+        let later = modified() + Duration::from_secs(1);
+        assert!(cache.lookup("a", "etag", later, 3).is_none());
+        assert!(cache.lookup("b", "etag", modified(), 3).is_none());
+    }
+
+    #[test]
+    fn reserve_evicts_the_least_recently_used_blocks() {
+        let dir = TestDir::new("evict");
+        let mut cache = DiskCache::open(&dir.0, 100).unwrap();
+        let first = store(&mut cache, "a", "etag", 0, 40);
+        let second = store(&mut cache, "b", "etag", 0, 40);
+        cache.lookup("a", "etag", modified(), 0);
+
+        let pending = cache.reserve("c", "etag", modified(), 0, 40).unwrap();
+        assert_eq!(pending.garbage, vec![second]);
+        assert_eq!(cache.used, 80);
+        assert!(cache.lookup("a", "etag", modified(), 0).is_some());
+        assert!(cache.lookup("b", "etag", modified(), 0).is_none());
+        assert!(first.exists());
+
+        assert!(cache.reserve("d", "etag", modified(), 0, 101).is_none());
+    }
+
+    #[test]
+    fn a_new_version_replaces_the_cached_one() {
+        let dir = TestDir::new("version");
+        let mut cache = DiskCache::open(&dir.0, 1024).unwrap();
+        let old = store(&mut cache, "a", "old", 0, 10);
+        let pending = cache.reserve("a", "new", modified(), 0, 10).unwrap();
+        assert_eq!(pending.garbage, vec![old.parent().unwrap().to_path_buf()]);
+        assert_eq!(cache.used, 10);
+    }
+
+    #[test]
+    fn validate_drops_other_versions() {
+        let dir = TestDir::new("validate");
+        let mut cache = DiskCache::open(&dir.0, 1024).unwrap();
+        let path = store(&mut cache, "a", "etag", 0, 10);
+
+        assert!(cache.validate("a", Some("etag"), modified()).is_empty());
+        assert!(cache.validate("b", Some("other"), modified()).is_empty());
+        let garbage = cache.validate("a", Some("other"), modified());
+        assert_eq!(garbage, vec![path.parent().unwrap().to_path_buf()]);
+        assert!(cache.lookup("a", "etag", modified(), 0).is_none());
+        assert_eq!(cache.used, 0);
+
+        store(&mut cache, "a", "etag", 0, 10);
+        assert_eq!(cache.validate("a", None, modified()).len(), 1);
+    }
+
+    #[test]
+    fn scan_indexes_blocks_and_leaves_unknown_entries_alone() {
+        let dir = TestDir::new("scan");
+        let path = {
+            let mut cache = DiskCache::open(&dir.0, 1024).unwrap();
+            store(&mut cache, "a", "etag", 0, 10)
+        };
+        let temporary = path.with_extension(TEMPORARY_EXTENSION);
+        fs::write(&temporary, b"partial").unwrap();
+        let unknown_file = path.with_file_name("notes.txt");
+        fs::write(&unknown_file, b"keep").unwrap();
+        let unknown_dir = path.parent().unwrap().with_file_name("unknown");
+        fs::create_dir_all(&unknown_dir).unwrap();
+        let outside = dir.0.join("user-file");
+        fs::write(&outside, b"keep").unwrap();
+
+        let mut cache = DiskCache::open(&dir.0, 1024).unwrap();
+        assert_eq!(cache.lookup("a", "etag", modified(), 0), Some(path));
+        assert_eq!(cache.used, 10);
+        assert!(!temporary.exists());
+        assert!(unknown_file.exists());
+        assert!(unknown_dir.exists());
+        assert!(outside.exists());
+    }
+
+    #[test]
+    fn open_evicts_down_to_the_maximum_size() {
+        let dir = TestDir::new("shrink");
+        let (first, second) = {
+            let mut cache = DiskCache::open(&dir.0, 1024).unwrap();
+            let first = store(&mut cache, "a", "etag", 0, 10);
+            let second = store(&mut cache, "b", "etag", 0, 10);
+            (first, second)
+        };
+        fs::File::options()
+            .write(true)
+            .open(&first)
+            .unwrap()
+            .set_modified(modified())
+            .unwrap();
+
+        let cache = DiskCache::open(&dir.0, 15).unwrap();
+        assert_eq!(cache.used, 10);
+        assert!(!first.exists());
+        assert!(second.exists());
+    }
+}
@@ -0,0 +1,367 @@
+use azure_core::Bytes;
+use fuser::FileType;
+use libc::{O_ACCMODE, O_RDONLY};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+use std::sync::{Arc, OnceLock};
+
+use crate::blob_container::APPEND_BLOCK_SIZE;
+
+/// Contiguous writes are coalesced up to this size before going to the blob,
+/// a full Append Block for append blobs
+const WRITE_BUFFER_SIZE: usize = APPEND_BLOCK_SIZE;
+
+/// Read-ahead window after the first sequential read, doubled on every further one
+const MIN_READAHEAD: u64 = 1024 * 1024;
+
+/// Write buffered on a handle that has not reached the blob yet
+#[derive(Debug, Clone)]
+pub struct PendingWrite {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl PendingWrite {
+    pub fn end(&self) -> u64 {
+        self.offset + self.data.len() as u64
+    }
+}
+
+/// State of one open() of a file
+#[derive(Debug, Clone)]
+pub struct FileHandle {
+    pub ino: u64,
+    pub flags: i32,
+    pub etag: Option<String>, // ETag of the blob when it was opened
+    read_offset: u64,         // End of the last read, to detect sequential access
+    readahead: u64,           // Current read-ahead window, 0 after a seek
+    pending: Option<PendingWrite>,
+    contents: Contents, // Whole blob downloaded on open, for small files
+}
+
+/// Slot for the blob downloaded on open, filled once the download completes
+pub type Contents = Arc<OnceLock<Bytes>>;
+
+impl FileHandle {
+    /// Returns true if the handle was opened for writing
+    pub fn is_writable(&self) -> bool {
+        self.flags & O_ACCMODE != O_RDONLY
+    }
+
+    /// Records a read and returns how much to prefetch after it.
+    ///
+    /// The window grows while reads continue where the previous one ended and
+    /// collapses on a seek.
+    pub fn record_read(&mut self, offset: u64, size: u64, max_readahead: u64) -> u64 {
+        self.readahead = if offset == self.read_offset {
+            (self.readahead * 2).max(MIN_READAHEAD).min(max_readahead)
+        } else {
+            0
+        };
+        self.read_offset = offset + size;
+        self.readahead
+    }
+
+    /// Slot keeping the whole blob in memory to serve every read of this handle
+    pub fn contents(&self) -> Contents {
+        self.contents.clone()
+    }
+
+    /// Serves a read from the blob downloaded on open, if there is one
+    pub fn read_contents(&self, offset: u64, size: u64) -> Option<Bytes> {
+        let contents = self.contents.get()?;
+        let start = (offset as usize).min(contents.len());
+        let end = ((offset + size) as usize).min(contents.len());
+        Some(contents.slice(start..end))
+    }
+
+    /// Forgets the downloaded blob once the file is modified, including one
+    /// whose download is still running
+    pub fn clear_contents(&mut self) {
+        self.contents = Contents::default();
+    }
+
+    /// Buffers a write, returning whatever must be written to the blob first.
+    ///
+    /// Writes that don't continue the buffer flush it, as does filling it up.
+    pub fn buffer_write(&mut self, offset: u64, data: &[u8]) -> Vec<PendingWrite> {
+        let mut ready = Vec::new();
+        match self.pending.as_mut() {
+            Some(pending)
+                if pending.end() == offset
+                    && pending.data.len() + data.len() <= WRITE_BUFFER_SIZE =>
+            {
+                pending.data.extend_from_slice(data);
+            }
+            _ => {
+                ready.extend(self.pending.take());
+                self.pending = Some(PendingWrite {
+                    offset,
+                    data: data.to_vec(),
+                });
+            }
+        }
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.data.len() >= WRITE_BUFFER_SIZE)
+        {
+            ready.extend(self.pending.take());
+        }
+        ready
+    }
+
+    /// Takes the buffered write so it can be written to the blob
+    pub fn take_pending(&mut self) -> Option<PendingWrite> {
+        self.pending.take()
+    }
+
+    /// End of the buffered write, which may lie past the size of the blob
+    pub fn pending_end(&self) -> Option<u64> {
+        self.pending.as_ref().map(PendingWrite::end)
+    }
+}
+
+/// Open file handles, keyed by the fh returned to the kernel
+#[derive(Debug, Default)]
+pub struct FileHandles {
+    handles: HashMap<u64, FileHandle>,
+    next_fh: u64,
+}
+
+impl FileHandles {
+    /// Registers a new open of an inode and returns its fh
+    pub fn open(&mut self, ino: u64, flags: i32, etag: Option<String>) -> u64 {
+        self.next_fh += 1;
+        let fh = self.next_fh;
+        self.handles.insert(
+            fh,
+            FileHandle {
+                ino,
+                flags,
+                etag,
+                read_offset: 0,
+                readahead: 0,
+                pending: None,
+                contents: Contents::default(),
+            },
+        );
+        fh
+    }
+
+    pub fn get_mut(&mut self, fh: u64) -> Option<&mut FileHandle> {
+        self.handles.get_mut(&fh)
+    }
+
+    pub fn release(&mut self, fh: u64) -> Option<FileHandle> {
+        self.handles.remove(&fh)
+    }
+
+    /// Returns true if any handle still has the inode open
+    pub fn is_open(&self, ino: u64) -> bool {
+        self.handles.values().any(|handle| handle.ino == ino)
+    }
+
+    /// All handles open on an inode
+    pub fn for_inode_mut(&mut self, ino: u64) -> impl Iterator<Item = &mut FileHandle> {
+        self.handles
+            .values_mut()
+            .filter(move |handle| handle.ino == ino)
+    }
+
+    /// All handles with buffered writes
+    pub fn pending_mut(&mut self) -> impl Iterator<Item = &mut FileHandle> {
+        self.handles
+            .values_mut()
+            .filter(|handle| handle.pending.is_some())
+    }
+}
+
+/// Entry of a directory listing, as returned by readdir
+#[derive(Debug, Clone)]
+pub struct DirectoryEntry {
+    pub ino: u64,
+    pub kind: FileType,
+    pub name: String,
+    pub cookie: i64, // Offset of the entry after this one, set by the handle
+}
+
+/// Cookies of "." and "..", other names are numbered after them
+const DOT_COOKIES: i64 = 2;
+
+/// Listing of an open directory, taken when reading starts.
+///
+/// readdir offsets are cookies handed out per name rather than positions, so
+/// reading resumes after the same name even once the listing is taken again.
+#[derive(Debug, Default)]
+pub struct DirectoryHandle {
+    listed: bool,
+    dots: Vec<DirectoryEntry>,
+    children: BTreeMap<String, DirectoryEntry>,
+    // Kept across rewinds so offsets from an earlier listing stay valid
+    cookies: HashMap<String, i64>,
+    names: HashMap<i64, String>,
+}
+
+impl DirectoryHandle {
+    /// Returns true once a listing was taken
+    pub fn is_listed(&self) -> bool {
+        self.listed
+    }
+
+    /// Replaces the listing, keeping the cookie of every name seen before
+    fn set(&mut self, entries: Vec<DirectoryEntry>) {
+        self.listed = true;
+        self.dots.clear();
+        self.children.clear();
+        for mut entry in entries {
+            if entry.name == "." || entry.name == ".." {
+                entry.cookie = self.dots.len() as i64 + 1;
+                self.dots.push(entry);
+                continue;
+            }
+            let next = DOT_COOKIES + 1 + self.cookies.len() as i64;
+            entry.cookie = *self.cookies.entry(entry.name.clone()).or_insert(next);
+            self.names.insert(entry.cookie, entry.name.clone());
+            self.children.insert(entry.name.clone(), entry);
+        }
+    }
+
+    /// Entries after the one a cookie was handed out for, in name order.
+    ///
+    /// Returns None for a cookie this handle never handed out.
+    pub fn after(&self, cookie: i64) -> Option<Vec<&DirectoryEntry>> {
+        let children = if cookie <= DOT_COOKIES {
+            self.children.range::<String, _>(..)
+        } else {
+            let name = self.names.get(&cookie)?;
+            self.children
+                .range::<String, _>((Bound::Excluded(name), Bound::Unbounded))
+        };
+        let dots = self.dots.iter().filter(|entry| entry.cookie > cookie);
+        Some(dots.chain(children.map(|(_, entry)| entry)).collect())
+    }
+}
+
+/// Directory listings of open directories, keyed by the fh returned to the kernel.
+///
+/// Each handle reads from the listing taken when reading started, so readdir
+/// offsets keep pointing at the same entries while the directory changes.
+#[derive(Debug, Default)]
+pub struct DirectoryHandles {
+    handles: HashMap<u64, DirectoryHandle>,
+    next_fh: u64,
+}
+
+impl DirectoryHandles {
+    /// Registers a new opendir and returns its fh
+    pub fn open(&mut self) -> u64 {
+        self.next_fh += 1;
+        self.handles
+            .insert(self.next_fh, DirectoryHandle::default());
+        self.next_fh
+    }
+
+    /// Replaces the listing of a handle, when reading starts over from the first entry
+    pub fn set(&mut self, fh: u64, entries: Vec<DirectoryEntry>) {
+        if let Some(handle) = self.handles.get_mut(&fh) {
+            handle.set(entries);
+        }
+    }
+
+    pub fn get(&self, fh: u64) -> Option<&DirectoryHandle> {
+        self.handles.get(&fh)
+    }
+
+    pub fn release(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(names: &[&str]) -> Vec<DirectoryEntry> {
+        names
+            .iter()
+            .enumerate()
+            .map(|(ino, name)| DirectoryEntry {
+                ino: ino as u64 + 1,
+                kind: FileType::RegularFile,
+                name: name.to_string(),
+                cookie: 0,
+            })
+            .collect()
+    }
+
+    fn names(entries: &[&DirectoryEntry]) -> Vec<String> {
+        entries.iter().map(|entry| entry.name.clone()).collect()
+    }
+
+    fn cookie(handle: &DirectoryHandle, name: &str) -> i64 {
+        handle
+            .after(0)
+            .unwrap()
+            .iter()
+            .find(|entry| entry.name == name)
+            .unwrap()
+            .cookie
+    }
+
+    #[test]
+    fn listings_start_with_the_dots_and_follow_name_order() {
+        let mut handle = DirectoryHandle::default();
+        assert!(!handle.is_listed());
+        handle.set(entries(&[".", "..", "b", "a", "c"]));
+        assert!(handle.is_listed());
+
+        let all = handle.after(0).unwrap();
+        assert_eq!(names(&all), vec![".", "..", "a", "b", "c"]);
+        assert_eq!(all[0].cookie, 1);
+        assert_eq!(all[1].cookie, 2);
+        assert!(all[2..].iter().all(|entry| entry.cookie > DOT_COOKIES));
+        assert_eq!(names(&handle.after(1).unwrap()), vec!["..", "a", "b", "c"]);
+        assert_eq!(names(&handle.after(2).unwrap()), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn reading_resumes_after_the_name_of_the_cookie() {
+        let mut handle = DirectoryHandle::default();
+        handle.set(entries(&[".", "..", "a", "b", "c"]));
+        let b = cookie(&handle, "b");
+        assert_eq!(names(&handle.after(b).unwrap()), vec!["c"]);
+        let c = cookie(&handle, "c");
+        assert!(handle.after(c).unwrap().is_empty());
+    }
+
+    #[test]
+    fn cookies_survive_a_new_listing() {
+        let mut handle = DirectoryHandle::default();
+        handle.set(entries(&[".", "..", "a", "b", "c"]));
+        let (a, b, c) = (
+            cookie(&handle, "a"),
+            cookie(&handle, "b"),
+            cookie(&handle, "c"),
+        );
+
+        // "b" was removed and "bb" added since the first listing
+        handle.set(entries(&[".", "..", "a", "bb", "c"]));
+        assert_eq!(cookie(&handle, "a"), a);
+        assert_eq!(cookie(&handle, "c"), c);
+        let bb = cookie(&handle, "bb");
+        assert!(![a, b, c].contains(&bb));
+        assert_eq!(names(&handle.after(b).unwrap()), vec!["bb", "c"]);
+
+        // A name that comes back gets its old cookie
+        handle.set(entries(&[".", "..", "b"]));
+        assert_eq!(cookie(&handle, "b"), b);
+    }
+
+    #[test]
+    fn unknown_cookies_are_rejected() {
+        let mut handle = DirectoryHandle::default();
+        handle.set(entries(&[".", "..", "a"]));
+        assert!(handle.after(100).is_none());
+    }
+}
@@ -1,24 +1,63 @@
 use crate::blob_container::{BlobContainer, BlobEntry};
+use crate::blob_read::BlobRead;
+use crate::file_handle::{
+    DirectoryEntry, DirectoryHandle, DirectoryHandles, FileHandle, FileHandles, PendingWrite,
+};
 use anyhow::Result;
+use azure_core::http::StatusCode;
+use fuser::consts::{FUSE_DO_READDIRPLUS, FUSE_READDIRPLUS_AUTO};
 use fuser::{
-    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
-    Request,
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request,
+    TimeOrNow, fuse_forget_one,
+};
+use libc::{
+    EACCES, EBADF, EBUSY, EEXIST, EINVAL, EIO, EISDIR, ENOENT, ENOTDIR, ENOTEMPTY, EROFS, ESTALE,
+    O_ACCMODE, O_APPEND, O_RDONLY, O_TRUNC, O_WRONLY, RENAME_EXCHANGE, RENAME_NOREPLACE,
 };
-use libc::{ENOENT, ENOTDIR};
 use log::{error, info, warn};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
 const TTL: Duration = Duration::from_secs(60); // Cache TTL for file attributes
 
+/// Maps an Azure Storage error to the closest errno, falling back to EIO
+fn to_errno(err: &anyhow::Error) -> i32 {
+    if let Some(errno) = err
+        .downcast_ref::<std::io::Error>()
+        .and_then(|err| err.raw_os_error())
+    {
+        return errno;
+    }
+    match err
+        .downcast_ref::<azure_core::Error>()
+        .and_then(|err| err.http_status())
+    {
+        Some(StatusCode::NotFound) => ENOENT,
+        Some(StatusCode::Conflict) => EBUSY,
+        Some(StatusCode::Forbidden) => EACCES,
+        // The blob changed on the service since it was last listed
+        Some(StatusCode::PreconditionFailed) => ESTALE,
+        _ => EIO,
+    }
+}
+
 pub struct BlobFilesystem {
     blob_container: BlobContainer,
+    runtime: Handle,
+    file_handles: FileHandles,
+    directory_handles: DirectoryHandles,
     user_id: u32,
     group_id: u32,
 }
 
 impl BlobFilesystem {
+    /// Creates the filesystem, which must happen on the runtime that will serve reads
     pub fn new(blob_container: BlobContainer, user_id: u32, group_id: u32) -> Self {
         Self {
             blob_container,
+            runtime: Handle::current(),
+            file_handles: FileHandles::default(),
+            directory_handles: DirectoryHandles::default(),
             user_id,
             group_id,
         }
@@ -35,9 +74,153 @@ impl BlobFilesystem {
         }
     }
 
+    /// Resolves a child of a directory to its entry
+    fn get_child(&self, parent: u64, name: &str) -> Result<&BlobEntry, i32> {
+        match self.blob_container.get_entry_by_inode(parent) {
+            Some(BlobEntry::Directory(dir)) => dir
+                .entries
+                .get(name)
+                .and_then(|&inode| self.blob_container.get_entry_by_inode(inode))
+                .ok_or(ENOENT),
+            Some(_) => Err(ENOTDIR),
+            None => Err(ENOENT),
+        }
+    }
+
+    /// Rejects a mutating callback on a read-only mount
+    fn check_writable(&self) -> Result<(), i32> {
+        if self.blob_container.is_read_only() {
+            return Err(EROFS);
+        }
+        Ok(())
+    }
+
+    /// Drops the preloaded contents of every handle on an inode that is being modified
+    fn clear_contents(&mut self, ino: u64) {
+        for handle in self.file_handles.for_inode_mut(ino) {
+            handle.clear_contents();
+        }
+    }
+
+    /// Writes buffered data to the blob
+    fn write_pending(&mut self, ino: u64, writes: Vec<PendingWrite>) -> Result<()> {
+        for write in writes {
+            self.blob_container
+                .write_blob(ino, write.offset as i64, &write.data)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the buffers of every handle open on an inode to the blob
+    fn flush_handles(&mut self, ino: u64) -> Result<()> {
+        let writes = self
+            .file_handles
+            .for_inode_mut(ino)
+            .filter_map(FileHandle::take_pending)
+            .collect();
+        self.write_pending(ino, writes)
+    }
+
+    /// Writes the buffer of one handle to the blob and commits it
+    fn flush_handle(&mut self, ino: u64, fh: u64) -> Result<()> {
+        let writes = self
+            .file_handles
+            .get_mut(fh)
+            .and_then(FileHandle::take_pending)
+            .into_iter()
+            .collect();
+        self.write_pending(ino, writes)?;
+        self.blob_container.flush_blob(ino)
+    }
+
+    /// Writes the buffers of all open handles, before paths change under them
+    fn flush_all_handles(&mut self) -> Result<()> {
+        let writes: Vec<(u64, PendingWrite)> = self
+            .file_handles
+            .pending_mut()
+            .filter_map(|handle| handle.take_pending().map(|write| (handle.ino, write)))
+            .collect();
+        for (ino, write) in writes {
+            self.write_pending(ino, vec![write])?;
+        }
+        Ok(())
+    }
+
+    /// Lists a directory with "." and ".." first and the other children sorted by name
+    fn list_directory(&self, ino: u64) -> Option<Vec<DirectoryEntry>> {
+        let dir = self.blob_container.get_directory(ino)?;
+        let (dots, children): (Vec<_>, Vec<_>) = dir
+            .entries
+            .iter()
+            .partition(|(name, _)| *name == "." || *name == "..");
+        let entries = dots
+            .into_iter()
+            .chain(children)
+            .filter_map(|(name, &inode)| {
+                let kind = match self.blob_container.get_entry_by_inode(inode)? {
+                    BlobEntry::Directory(_) => FileType::Directory,
+                    BlobEntry::File(_) => FileType::RegularFile,
+                };
+                Some(DirectoryEntry {
+                    ino: inode,
+                    kind,
+                    name: name.clone(),
+                    cookie: 0,
+                })
+            })
+            .collect();
+        Some(entries)
+    }
+
+    /// Takes a new listing for a directory handle when reading starts (again, after a rewinddir)
+    fn start_listing(&mut self, ino: u64, fh: u64) -> Result<(), i32> {
+        self.blob_container.apply_listings();
+        if let Err(err) = self.blob_container.refresh_directory(ino) {
+            error!("Failed to list directory inode {ino}: {err}");
+            return Err(to_errno(&err));
+        }
+        match self.list_directory(ino) {
+            Some(entries) => {
+                self.directory_handles.set(fh, entries);
+                Ok(())
+            }
+            None => {
+                error!("Inode {ino} not found");
+                Err(ENOTDIR)
+            }
+        }
+    }
+
+    /// Entries of a directory handle after the given offset, listing the directory
+    /// when reading starts
+    fn read_listing(&mut self, ino: u64, fh: u64, offset: i64) -> Result<Vec<DirectoryEntry>, i32> {
+        let Some(listed) = self
+            .directory_handles
+            .get(fh)
+            .map(DirectoryHandle::is_listed)
+        else {
+            error!("Directory handle {fh} not found");
+            return Err(EBADF);
+        };
+        if offset == 0 || !listed {
+            self.start_listing(ino, fh)?;
+        }
+        let entries = self
+            .directory_handles
+            .get(fh)
+            .and_then(|handle| handle.after(offset));
+        match entries {
+            Some(entries) => Ok(entries.into_iter().cloned().collect()),
+            None => {
+                error!("Offset {offset} was not handed out for directory handle {fh}");
+                Err(EINVAL)
+            }
+        }
+    }
+
     fn get_attrs(&self, entry: &BlobEntry) -> FileAttr {
         // Find blob by inode and convert to file attributes
-        let mut attr: FileAttr = entry.into();
+        let mut attr = self.blob_container.attributes(entry);
         attr.uid = self.user_id;
         attr.gid = self.group_id;
         attr
@@ -47,8 +230,18 @@ impl BlobFilesystem {
 impl Filesystem for BlobFilesystem {
     fn getattr(&mut self, _req: &Request, ino: u64, _: Option<u64>, reply: ReplyAttr) {
         info!("getattr(ino={ino})");
+        self.blob_container.apply_listings();
 
-        let attr = self.get_inode_attrs(ino);
+        let attr = self.get_inode_attrs(ino).map(|mut attr| {
+            // Buffered writes may extend the file past the blob's size
+            let pending_end = self
+                .file_handles
+                .for_inode_mut(ino)
+                .filter_map(|handle| handle.pending_end())
+                .max();
+            attr.size = attr.size.max(pending_end.unwrap_or(0));
+            attr
+        });
         match attr {
             Some(attr) => {
                 reply.attr(&TTL, &attr);
@@ -60,51 +253,166 @@ impl Filesystem for BlobFilesystem {
         }
     }
 
-    fn init(&mut self, _req: &Request, _: &mut KernelConfig) -> Result<(), i32> {
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        info!("setattr(ino={ino}, size={size:?}, mtime={mtime:?})");
+        let changes = mode.is_some()
+            || uid.is_some()
+            || gid.is_some()
+            || size.is_some()
+            || atime.is_some()
+            || mtime.is_some();
+        if changes && let Err(errno) = self.check_writable() {
+            return reply.error(errno);
+        }
+
+        if size.is_some() {
+            self.clear_contents(ino);
+        }
+        if let Some(size) = size
+            && let Err(err) = self
+                .flush_handles(ino)
+                .and_then(|()| self.blob_container.set_blob_size(ino, size, fh.is_some()))
+        {
+            error!("Failed to set size of inode {ino}: {err}");
+            return reply.error(to_errno(&err));
+        }
+        if let Some(mtime) = mtime {
+            let mtime = match mtime {
+                TimeOrNow::SpecificTime(time) => time,
+                TimeOrNow::Now => SystemTime::now(),
+            };
+            if let Err(err) = self
+                .flush_handles(ino)
+                .and_then(|()| self.blob_container.set_last_modified(ino, mtime))
+            {
+                error!("Failed to set modification time of inode {ino}: {err}");
+                return reply.error(to_errno(&err));
+            }
+        }
+
+        // Ownership and permissions are fixed for the whole mount
+        match self.get_inode_attrs(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> Result<(), i32> {
         info!("Initializing Azure Blob FUSE filesystem...");
+        // Listings come with attributes, saving a lookup per entry
+        if let Err(unsupported) =
+            config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO)
+        {
+            warn!("Kernel does not support readdirplus capabilities {unsupported:#x}");
+        }
         Ok(())
     }
 
     fn destroy(&mut self) {
+        self.blob_container.save_snapshot_now();
         info!("Blob Filesystem destroyed cleanly");
     }
 
+    fn opendir(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
+        info!("opendir(ino={ino})");
+        if self.blob_container.get_directory(ino).is_none() {
+            return reply.error(ENOTDIR);
+        }
+        reply.opened(self.directory_handles.open(), 0);
+    }
+
     fn readdir(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         mut reply: ReplyDirectory,
     ) {
-        info!("readdir(ino={ino}, offset={offset})");
-
-        let entry = self.blob_container.get_directory(ino);
-        match entry {
-            Some(dir) => {
-                let mut current_offset = offset;
-                for (name, inode) in dir.entries.iter().skip(offset as usize) {
-                    let entry = self.blob_container.get_entry_by_inode(*inode).unwrap();
-                    let kind = match entry {
-                        BlobEntry::Directory(_) => FileType::Directory,
-                        _ => FileType::RegularFile,
-                    };
-
-                    let full = reply.add(*inode, current_offset + 1, kind, name);
-                    if full {
-                        info!("Directory listing buffer full at offset {current_offset}");
-                        break;
-                    }
-                    current_offset += 1;
-                }
+        info!("readdir(ino={ino}, fh={fh}, offset={offset})");
+        let entries = match self.read_listing(ino, fh, offset) {
+            Ok(entries) => entries,
+            Err(errno) => return reply.error(errno),
+        };
+        for entry in entries {
+            if reply.add(entry.ino, entry.cookie, entry.kind, &entry.name) {
+                info!("Directory listing buffer full at {}", entry.name);
+                break;
+            }
+        }
+        reply.ok();
+    }
 
-                reply.ok();
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        info!("readdirplus(ino={ino}, fh={fh}, offset={offset})");
+        let entries = match self.read_listing(ino, fh, offset) {
+            Ok(entries) => entries,
+            Err(errno) => return reply.error(errno),
+        };
+        let mut referenced = Vec::new();
+        for entry in entries {
+            // Entries removed since the listing was taken are skipped
+            let Some(attr) = self.get_inode_attrs(entry.ino) else {
+                continue;
+            };
+            let generation = self.blob_container.generation(entry.ino);
+            if reply.add(
+                entry.ino,
+                entry.cookie,
+                &entry.name,
+                &TTL,
+                &attr,
+                generation,
+            ) {
+                info!("Directory listing buffer full at {}", entry.name);
+                break;
             }
-            None => {
-                error!("Inode {ino} not found");
-                reply.error(ENOTDIR);
+            // Every entry but "." and ".." counts as a lookup
+            if entry.name != "." && entry.name != ".." {
+                referenced.push(entry.ino);
             }
         }
+        reply.ok();
+        for ino in referenced {
+            self.blob_container.remember(ino);
+        }
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        info!("releasedir(ino={ino}, fh={fh})");
+        self.directory_handles.release(fh);
+        reply.ok();
     }
 
     fn lookup(
@@ -116,13 +424,26 @@ impl Filesystem for BlobFilesystem {
     ) {
         let name = name.to_string_lossy();
         info!("lookup(parent={parent}, name={name})");
+        self.blob_container.apply_listings();
+        if let Err(err) = self.blob_container.refresh_directory(parent) {
+            error!("Failed to list directory inode {parent}: {err}");
+            return reply.error(to_errno(&err));
+        }
+        // The blob may have been created since the directory was listed
+        if self.get_child(parent, &name).err() == Some(ENOENT)
+            && let Err(err) = self.blob_container.lookup_missing(parent, &name)
+        {
+            error!("Failed to look up {name} in directory inode {parent}: {err}");
+            return reply.error(to_errno(&err));
+        }
         let entry = self.blob_container.get_entry_by_inode(parent);
         if let Some(BlobEntry::Directory(dir)) = entry {
             let entry = dir.entries.get(name.as_ref());
             if let Some(&inode) = entry {
                 if let Some(blob_entry) = self.blob_container.get_entry_by_inode(inode) {
                     let attrs = self.get_attrs(blob_entry);
-                    reply.entry(&TTL, &attrs, 0);
+                    reply.entry(&TTL, &attrs, self.blob_container.generation(inode));
+                    self.blob_container.remember(inode);
                 } else {
                     error!("Blob entry for inode {inode} not found");
                     reply.error(ENOENT);
@@ -137,27 +458,431 @@ impl Filesystem for BlobFilesystem {
         }
     }
 
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        info!("forget(ino={ino}, nlookup={nlookup})");
+        if self.blob_container.forget(ino, nlookup) && !self.file_handles.is_open(ino) {
+            self.blob_container.evict(ino);
+        }
+    }
+
+    fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        info!("batch_forget({} inodes)", nodes.len());
+        // Children and their directory can be forgotten together, so evict once all counts dropped
+        let unreferenced: Vec<u64> = nodes
+            .iter()
+            .filter(|node| self.blob_container.forget(node.nodeid, node.nlookup))
+            .map(|node| node.nodeid)
+            .collect();
+        for ino in unreferenced {
+            if !self.file_handles.is_open(ino) {
+                self.blob_container.evict(ino);
+            }
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        info!("open(ino={ino}, flags={flags:#x})");
+        self.blob_container.apply_listings();
+        if (flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0)
+            && let Err(errno) = self.check_writable()
+        {
+            return reply.error(errno);
+        }
+        // Empty files opened for appending become append blobs
+        if flags & O_ACCMODE != O_RDONLY
+            && let Err(err) = self
+                .blob_container
+                .open_for_writing(ino, flags & O_APPEND != 0)
+        {
+            error!("Failed to open inode {ino} for writing: {err}");
+            return reply.error(to_errno(&err));
+        }
+        let etag = match self.blob_container.get_entry_by_inode(ino) {
+            Some(BlobEntry::File(blob)) => blob.etag.clone(),
+            Some(BlobEntry::Directory(_)) => return reply.error(EISDIR),
+            None => return reply.error(ENOENT),
+        };
+        self.blob_container.open_blob(ino);
+        let fh = self.file_handles.open(ino, flags, etag);
+
+        // Small files are read whole once, then every read of the handle is local
+        if flags & O_ACCMODE != O_WRONLY && flags & O_TRUNC == 0 {
+            let contents = self
+                .file_handles
+                .get_mut(fh)
+                .map(|handle| handle.contents());
+            match (self.blob_container.preload_blob(ino), contents) {
+                (Ok(Some(BlobRead::Ready(data))), Some(contents)) => {
+                    let _ = contents.set(data);
+                }
+                (Ok(Some(BlobRead::Fetch(read))), Some(contents)) => {
+                    // Reply once the download completes, without blocking other requests
+                    self.runtime.spawn(async move {
+                        match read.run().await {
+                            Ok(data) => {
+                                let _ = contents.set(data);
+                            }
+                            Err(err) => warn!("Failed to preload inode {ino}: {err}"),
+                        }
+                        reply.opened(fh, 0);
+                    });
+                    return;
+                }
+                (Ok(_), _) => {}
+                (Err(err), _) => warn!("Failed to preload inode {ino}: {err}"),
+            }
+        }
+        reply.opened(fh, 0);
+    }
+
     fn read(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
-        info!("read(ino={ino}, offset={offset}, size={size})");
-        let result = self.blob_container.download_blob(ino, offset, size);
+        let max_readahead = self.blob_container.readahead();
+        let readahead = self.file_handles.get_mut(fh).map_or(0, |handle| {
+            handle.record_read(offset as u64, size as u64, max_readahead)
+        });
+        info!("read(ino={ino}, fh={fh}, offset={offset}, size={size}, readahead={readahead})");
+        self.blob_container.apply_changed_blobs();
+        if let Some(data) = self
+            .file_handles
+            .get_mut(fh)
+            .and_then(|handle| handle.read_contents(offset as u64, size as u64))
+        {
+            return reply.data(&data);
+        }
 
-        match result {
-            Ok(data) => {
-                reply.data(&data);
+        // Reads must see writes still buffered on any handle
+        if let Err(err) = self.flush_handles(ino) {
+            error!("Failed to write buffered data before read: {err}");
+            return reply.error(to_errno(&err));
+        }
+        match self.blob_container.start_read(ino, offset, size) {
+            Ok(BlobRead::Ready(data)) => reply.data(&data),
+            Ok(BlobRead::Fetch(read)) => {
+                // Reply from the runtime so other requests proceed while this one downloads
+                self.runtime.spawn(async move {
+                    match read.run().await {
+                        Ok(data) => reply.data(&data),
+                        Err(err) => {
+                            error!("Failed to read blob: {err}");
+                            reply.error(to_errno(&err));
+                        }
+                    }
+                });
             }
             Err(err) => {
                 error!("Failed to read blob: {err}");
-                reply.error(libc::EIO);
+                return reply.error(to_errno(&err));
+            }
+        }
+        if readahead > 0 {
+            self.blob_container
+                .prefetch(ino, offset as u64 + size as u64, readahead);
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        _mode: u32,
+        _umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        let name = name.to_string_lossy();
+        info!("create(parent={parent}, name={name}, flags={flags:#x})");
+        if let Err(errno) = self.check_writable() {
+            return reply.error(errno);
+        }
+        let attrs = self
+            .blob_container
+            .create_file(parent, &name, flags & O_APPEND != 0)
+            .map(|inode| self.get_inode_attrs(inode));
+        match attrs {
+            Ok(Some(attrs)) => {
+                let fh = self.file_handles.open(attrs.ino, flags, None);
+                let generation = self.blob_container.generation(attrs.ino);
+                reply.created(&TTL, &attrs, generation, fh, 0);
+                self.blob_container.remember(attrs.ino);
+            }
+            Ok(None) => reply.error(ENOENT),
+            Err(err) => {
+                error!("Failed to create blob '{name}': {err}");
+                reply.error(to_errno(&err));
+            }
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        info!(
+            "write(ino={ino}, fh={fh}, offset={offset}, size={})",
+            data.len()
+        );
+        if let Err(errno) = self.check_writable() {
+            return reply.error(errno);
+        }
+        let writes = match self.file_handles.get_mut(fh) {
+            Some(handle) if handle.is_writable() => handle.buffer_write(offset as u64, data),
+            _ => return reply.error(EBADF),
+        };
+        self.clear_contents(ino);
+        match self.write_pending(ino, writes) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(err) => {
+                error!("Failed to write blob: {err}");
+                reply.error(to_errno(&err));
+            }
+        }
+    }
+
+    fn flush(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _lock_owner: u64,
+        reply: ReplyEmpty,
+    ) {
+        info!("flush(ino={ino}, fh={fh})");
+        match self.flush_handle(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                error!("Failed to flush blob: {err}");
+                reply.error(to_errno(&err));
+            }
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        info!("fsync(ino={ino}, fh={fh}, datasync={datasync})");
+        match self.flush_handle(ino, fh) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                error!("Failed to sync blob: {err}");
+                reply.error(to_errno(&err));
+            }
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        info!("release(ino={ino}, fh={fh})");
+        let mut writes = Vec::new();
+        if let Some(mut handle) = self.file_handles.release(fh) {
+            writes.extend(handle.take_pending());
+            if let Some(BlobEntry::File(blob)) = self.blob_container.get_entry_by_inode(ino)
+                && blob.etag.is_some()
+                && blob.etag != handle.etag
+            {
+                info!(
+                    "Blob {} changed while open: {:?} -> {:?}",
+                    blob.name, handle.etag, blob.etag
+                );
+            }
+        }
+
+        // The block list stays loaded while other handles still have the file open
+        let result = self.write_pending(ino, writes).and_then(|()| {
+            if self.file_handles.is_open(ino) {
+                self.blob_container.flush_blob(ino)
+            } else {
+                self.blob_container.release_blob(ino)
+            }
+        });
+        match result {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                error!("Failed to release blob: {err}");
+                reply.error(to_errno(&err));
+            }
+        }
+    }
+
+    fn unlink(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_string_lossy();
+        info!("unlink(parent={parent}, name={name})");
+        if let Err(errno) = self.check_writable() {
+            return reply.error(errno);
+        }
+        let ino = match self.get_child(parent, &name) {
+            Ok(BlobEntry::File(blob)) => blob.inode,
+            Ok(BlobEntry::Directory(_)) => return reply.error(EISDIR),
+            Err(errno) => return reply.error(errno),
+        };
+        // Data still buffered for the file is dropped along with it
+        for handle in self.file_handles.for_inode_mut(ino) {
+            handle.take_pending();
+        }
+
+        match self.blob_container.delete_file(parent, &name) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                error!("Failed to delete blob '{name}': {err}");
+                reply.error(to_errno(&err));
+            }
+        }
+    }
+
+    fn rmdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_string_lossy();
+        info!("rmdir(parent={parent}, name={name})");
+        if let Err(errno) = self.check_writable() {
+            return reply.error(errno);
+        }
+        match self.get_child(parent, &name) {
+            Ok(BlobEntry::Directory(dir)) if dir.is_empty() => {}
+            Ok(BlobEntry::Directory(_)) => return reply.error(ENOTEMPTY),
+            Ok(BlobEntry::File(_)) => return reply.error(ENOTDIR),
+            Err(errno) => return reply.error(errno),
+        }
+
+        match self.blob_container.remove_directory(parent, &name) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                error!("Failed to remove directory '{name}': {err}");
+                reply.error(to_errno(&err));
+            }
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        newparent: u64,
+        newname: &std::ffi::OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_string_lossy();
+        let newname = newname.to_string_lossy();
+        info!("rename(parent={parent}, name={name}, newparent={newparent}, newname={newname})");
+        if let Err(errno) = self.check_writable() {
+            return reply.error(errno);
+        }
+        if flags & RENAME_EXCHANGE != 0 {
+            return reply.error(EINVAL);
+        }
+
+        let source_is_dir = match self.get_child(parent, &name) {
+            Ok(entry) => matches!(entry, BlobEntry::Directory(_)),
+            Err(errno) => return reply.error(errno),
+        };
+        match self.get_child(newparent, &newname) {
+            Err(ENOENT) => {}
+            Err(errno) => return reply.error(errno),
+            Ok(_) if flags & RENAME_NOREPLACE != 0 => return reply.error(EEXIST),
+            Ok(BlobEntry::File(_)) if source_is_dir => return reply.error(ENOTDIR),
+            Ok(BlobEntry::File(_)) => {}
+            Ok(BlobEntry::Directory(_)) if !source_is_dir => return reply.error(EISDIR),
+            Ok(BlobEntry::Directory(dir)) => {
+                // A lazily listed directory looks empty until it is listed
+                let inode = dir.inode;
+                if let Err(err) = self.blob_container.refresh_directory(inode) {
+                    error!("Failed to list directory inode {inode}: {err}");
+                    return reply.error(to_errno(&err));
+                }
+                let empty = self
+                    .blob_container
+                    .get_directory(inode)
+                    .is_none_or(|dir| dir.is_empty());
+                let resumable = self
+                    .blob_container
+                    .is_pending_rename(parent, &name, newparent, &newname);
+                if !empty && !resumable {
+                    return reply.error(ENOTEMPTY);
+                }
+            }
+        }
+
+        match self.flush_all_handles().and_then(|()| {
+            self.blob_container
+                .rename(parent, &name, newparent, &newname)
+        }) {
+            Ok(()) => reply.ok(),
+            Err(err) => {
+                error!("Failed to rename '{name}' to '{newname}': {err:#}");
+                reply.error(to_errno(&err));
+            }
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &std::ffi::OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_string_lossy();
+        info!("mkdir(parent={parent}, name={name})");
+        if let Err(errno) = self.check_writable() {
+            return reply.error(errno);
+        }
+        match self.get_child(parent, &name) {
+            Ok(_) => return reply.error(EEXIST),
+            Err(ENOENT) => {}
+            Err(errno) => return reply.error(errno),
+        }
+
+        let attrs = self
+            .blob_container
+            .create_directory(parent, &name)
+            .map(|inode| self.get_inode_attrs(inode));
+        match attrs {
+            Ok(Some(attrs)) => {
+                reply.entry(&TTL, &attrs, self.blob_container.generation(attrs.ino));
+                self.blob_container.remember(attrs.ino);
+            }
+            Ok(None) => reply.error(ENOENT),
+            Err(err) => {
+                error!("Failed to create directory '{name}': {err}");
+                reply.error(to_errno(&err));
             }
         }
     }
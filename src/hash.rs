@@ -0,0 +1,6 @@
+/// Stable FNV-1a hash of a blob name, the same on every mount
+pub fn hash_name(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,80 @@
+use anyhow::Result;
+use azure_core::time::{OffsetDateTime, parse_rfc3339};
+use azure_storage_blob::models::BlobType;
+use log::info;
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
+
+use crate::rest_client::BlobRestClient;
+
+/// Metadata key marking a zero-length blob as a directory, as used by other Azure FUSE drivers
+pub const FOLDER_METADATA: &str = "hdi_isfolder";
+
+/// Metadata key holding a modification time set through the mount, as used by rclone
+pub const MTIME_METADATA: &str = "mtime";
+
+/// A blob as returned by a container listing
+#[derive(Debug, Clone)]
+pub struct ListedBlob {
+    pub name: String,
+    pub size: u64,
+    pub last_modified: SystemTime,
+    pub etag: Option<String>,
+    pub blob_type: BlobType,
+    pub marker: bool, // Zero-length hdi_isfolder blob standing for a directory
+}
+
+/// Every blob of the container at the time it was listed
+#[derive(Debug)]
+pub struct Listing {
+    pub blobs: Vec<ListedBlob>,
+    pub started: SystemTime, // Entries changed locally after this are left alone when merging
+}
+
+/// Blobs and directories directly under a prefix, from a listing with a delimiter
+#[derive(Debug)]
+pub struct DirectoryListing {
+    pub blobs: Vec<ListedBlob>,
+    pub directories: Vec<String>, // Full paths, without the trailing delimiter
+    pub started: SystemTime,
+}
+
+/// Checks blob metadata for the hdi_isfolder flag of a directory marker
+pub fn has_folder_metadata(metadata: &HashMap<String, String>) -> bool {
+    metadata.iter().any(|(key, value)| {
+        key.eq_ignore_ascii_case(FOLDER_METADATA) && value.eq_ignore_ascii_case("true")
+    })
+}
+
+/// Modification time of a blob, from its mtime metadata if set and Last-Modified otherwise
+pub fn modification_time(
+    metadata: &HashMap<String, String>,
+    last_modified: OffsetDateTime,
+) -> SystemTime {
+    let mtime = metadata
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(MTIME_METADATA))
+        .and_then(|(_, value)| parse_rfc3339(value).ok());
+    SystemTime::from(mtime.unwrap_or(last_modified))
+}
+
+/// Lists every blob in the container with a flat listing.
+///
+/// Metadata comes with the listing, so directory markers are recognized
+/// without a request per zero-length blob.
+pub async fn list_container(rest_client: &BlobRestClient) -> Result<Listing> {
+    info!("Listing all blobs in the container");
+    let listing_start = Instant::now();
+    let directory_listing = rest_client.list_blobs("", None, None).await?;
+    let listing = Listing {
+        blobs: directory_listing.blobs,
+        started: directory_listing.started,
+    };
+
+    info!(
+        "Listed {} blobs in {:.2}s",
+        listing.blobs.len(),
+        listing_start.elapsed().as_secs_f64()
+    );
+    Ok(listing)
+}
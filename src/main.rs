@@ -1,5 +1,15 @@
 mod blob_container;
+mod blob_read;
+mod blob_upload;
+mod block_cache;
+mod disk_cache;
+mod file_handle;
 mod filesystem;
+mod hash;
+mod listing;
+mod page_blob;
+mod rest_client;
+mod snapshot;
 
 use anyhow::{Context, Result};
 use azure_core::credentials::TokenCredential;
@@ -8,11 +18,13 @@ use azure_storage_blob::clients::BlobServiceClient;
 use clap::Parser;
 use essi_ffmpeg::FFmpeg;
 use filesystem::BlobFilesystem;
+use fuser::MountOption;
 use libc::{getgid, getuid};
 use log::info;
-use std::{io::Read, path::PathBuf, process::Stdio, sync::Arc};
+use std::{io::Read, path::PathBuf, process::Stdio, sync::Arc, time::Duration};
 
-use crate::blob_container::BlobContainer;
+use crate::blob_container::{BlobContainer, ContainerOptions};
+use crate::rest_client::BlobRestClient;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -39,6 +51,64 @@ struct Args {
 
     #[arg(short, long)]
     input_file: PathBuf,
+
+    /// Blob name prefix whose new files are created as append blobs (repeatable)
+    #[arg(long)]
+    append_prefix: Vec<String>,
+
+    /// Mount the container read-only, rejecting every change with EROFS.
+    /// Credentials are not narrowed, give the identity a reader role for that
+    #[arg(long)]
+    read_only: bool,
+
+    /// Memory budget of the block read cache in MiB
+    #[arg(long, default_value_t = 256)]
+    cache_memory_mb: u64,
+
+    /// Directory where downloaded blocks are kept across mounts, in a subdirectory of their own
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+
+    /// Maximum size of the disk cache in MiB
+    #[arg(long, default_value_t = 10240)]
+    cache_dir_max_mb: u64,
+
+    /// Maximum read-ahead window for sequential reads in MiB, 0 to disable
+    #[arg(long, default_value_t = 16)]
+    readahead_mb: u64,
+
+    /// Size of the ranges large downloads are split into in MiB
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u64).range(1..))]
+    download_chunk_mb: u64,
+
+    /// Maximum number of concurrent ranged downloads per read
+    #[arg(long, default_value_t = 8, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    download_concurrency: usize,
+
+    /// Files up to this size in KiB are downloaded whole when opened, 0 to disable
+    #[arg(long, default_value_t = 256)]
+    preload_kb: u64,
+
+    /// Directory keeping a snapshot of the namespace, to remount without listing first
+    #[arg(long)]
+    snapshot_dir: Option<PathBuf>,
+
+    /// Seconds between background listings that pick up remote changes, 0 to disable
+    #[arg(long, default_value_t = 300)]
+    refresh_secs: u64,
+
+    /// List directories as they are browsed instead of the whole container on mount.
+    /// Only then are directories the kernel forgot released from memory
+    #[arg(long)]
+    lazy_listing: bool,
+
+    /// Seconds a directory listing is trusted before it is listed again, with --lazy-listing
+    #[arg(long, default_value_t = 60)]
+    listing_ttl_secs: u64,
+
+    /// Seconds a name that was not found on the service is remembered as missing
+    #[arg(long, default_value_t = 5)]
+    negative_ttl_secs: u64,
 }
 
 #[tokio::main]
@@ -58,20 +128,46 @@ async fn main() -> Result<()> {
         args.user_id, args.group_id
     );
 
-    // Create Azure credentials using DefaultAzureCredential
+    // Create Azure credentials using DefaultAzureCredential. Entra ID tokens carry no
+    // read-only scope, what they allow comes from the roles of the identity
     let credential: Arc<dyn TokenCredential> = DefaultAzureCredential::new()?;
 
     // Create blob service client
     let storage_url = format!("https://{}.blob.core.windows.net", args.storage_account);
-    let blob_service_client = BlobServiceClient::new(&storage_url, credential, None)?;
+    let blob_service_client = BlobServiceClient::new(&storage_url, credential.clone(), None)?;
+    let rest_client = BlobRestClient::new(&storage_url, &args.container, credential)?;
+    let snapshot_path = args
+        .snapshot_dir
+        .map(|dir| dir.join(format!("{}.{}.json", args.storage_account, args.container)));
     let container_client = blob_service_client.blob_container_client(args.container);
 
     // Create filesystem
-    let blob_container = BlobContainer::new(container_client).await?;
+    let options = ContainerOptions {
+        append_prefixes: args.append_prefix,
+        read_only: args.read_only,
+        cache_memory: args.cache_memory_mb * 1024 * 1024,
+        cache_dir: args.cache_dir,
+        cache_dir_max_size: args.cache_dir_max_mb * 1024 * 1024,
+        readahead: args.readahead_mb * 1024 * 1024,
+        download_chunk_size: args.download_chunk_mb * 1024 * 1024,
+        download_concurrency: args.download_concurrency,
+        preload_size: args.preload_kb * 1024,
+        snapshot_path,
+        refresh_interval: Duration::from_secs(args.refresh_secs),
+        lazy_listing: args.lazy_listing,
+        listing_ttl: Duration::from_secs(args.listing_ttl_secs),
+        negative_ttl: Duration::from_secs(args.negative_ttl_secs),
+    };
+    let blob_container = BlobContainer::new(container_client, rest_client, options).await?;
     let fs = BlobFilesystem::new(blob_container, args.user_id, args.group_id);
 
     // Mount the filesystem
-    let handle = fuser::spawn_mount2(fs, &args.mountpoint, &[])
+    let mut mount_options = Vec::new();
+    if args.read_only {
+        info!("Mounting read-only");
+        mount_options.push(MountOption::RO);
+    }
+    let handle = fuser::spawn_mount2(fs, &args.mountpoint, &mount_options)
         .with_context(|| format!("Failed to mount filesystem at {:?}", args.mountpoint))?;
 
     let input_file = args.mountpoint.join(&args.input_file);
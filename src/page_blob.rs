@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use azure_core::Bytes;
+use azure_core::http::RequestContent;
+use azure_storage_blob::models::BlobClientDownloadOptions;
+use azure_storage_blob::{BlobClient, BlobContainerClient, format_page_range};
+use log::info;
+use std::ops::Range;
+
+use crate::rest_client::BlobRestClient;
+
+/// Page blobs are written in 512 byte pages
+pub const PAGE_SIZE: u64 = 512;
+
+/// Maximum size of a single Put Page call
+const MAX_PUT_PAGE: u64 = 4 * 1024 * 1024;
+
+/// Valid (non-sparse) byte ranges of a page blob, sorted and non-overlapping
+#[derive(Debug, Clone, Default)]
+pub struct PageRanges {
+    ranges: Vec<Range<u64>>,
+}
+
+impl PageRanges {
+    /// Fetches the valid page ranges with Get Page Ranges
+    pub async fn fetch(rest_client: &BlobRestClient, name: &str) -> Result<Self> {
+        let mut page_ranges = Self::default();
+        for range in rest_client.get_page_ranges(name).await? {
+            page_ranges.insert(range);
+        }
+        info!(
+            "Page blob {name} has {} valid page ranges",
+            page_ranges.ranges.len()
+        );
+        Ok(page_ranges)
+    }
+
+    /// Marks a range as written, merging it with adjacent ranges
+    pub fn insert(&mut self, range: Range<u64>) {
+        let mut merged = range;
+        self.ranges.retain(|existing| {
+            if existing.start <= merged.end && merged.start <= existing.end {
+                merged = merged.start.min(existing.start)..merged.end.max(existing.end);
+                false
+            } else {
+                true
+            }
+        });
+        let index = self
+            .ranges
+            .partition_point(|existing| existing.start < merged.start);
+        self.ranges.insert(index, merged);
+    }
+
+    /// Drops everything at or beyond `size`
+    pub fn truncate(&mut self, size: u64) {
+        self.ranges.retain(|range| range.start < size);
+        if let Some(last) = self.ranges.last_mut() {
+            last.end = last.end.min(size);
+        }
+    }
+
+    /// Parts of a range that hold written pages, the only parts that are downloaded
+    fn valid_parts(&self, range: &Range<u64>) -> Vec<Range<u64>> {
+        self.ranges
+            .iter()
+            .filter(|valid| valid.start < range.end && range.start < valid.end)
+            .map(|valid| valid.start.max(range.start)..valid.end.min(range.end))
+            .collect()
+    }
+
+    /// Builds a range from its downloaded parts, with zeros everywhere else
+    fn zero_filled(range: &Range<u64>, parts: Vec<(Range<u64>, Bytes)>) -> Bytes {
+        let mut data = vec![0; (range.end - range.start) as usize];
+        for (part, bytes) in parts {
+            let offset = (part.start - range.start) as usize;
+            data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        }
+        Bytes::from(data)
+    }
+
+    /// Reads a range, downloading only the valid pages and filling sparse regions with zeros
+    pub async fn read(&self, client: &BlobClient, name: &str, range: Range<u64>) -> Result<Bytes> {
+        let mut parts = Vec::new();
+        for part in self.valid_parts(&range) {
+            let options = BlobClientDownloadOptions {
+                range: Some(format!("bytes={}-{}", part.start, part.end - 1)),
+                ..Default::default()
+            };
+            let bytes = client
+                .download(Some(options))
+                .await
+                .context(format!("Failed to download pages of blob: {name}"))?
+                .into_raw_body()
+                .collect()
+                .await?;
+            parts.push((part, bytes));
+        }
+        Ok(Self::zero_filled(&range, parts))
+    }
+
+    /// Writes data with Put Page, reading the partial pages at either end first.
+    ///
+    /// The blob is resized to the next page boundary if the write goes past its end.
+    pub async fn write(
+        &mut self,
+        client: &BlobContainerClient,
+        name: &str,
+        size: &mut u64,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let end = offset + data.len() as u64;
+        let aligned_start = offset / PAGE_SIZE * PAGE_SIZE;
+        let aligned_end = end.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let blob_client = client.blob_client(name.to_string());
+        let page_client = blob_client.page_blob_client();
+
+        if aligned_end > *size {
+            page_client
+                .resize(aligned_end, None)
+                .await
+                .context(format!("Failed to resize page blob: {name}"))?;
+            *size = aligned_end;
+        }
+
+        let mut pages = vec![0; (aligned_end - aligned_start) as usize];
+        if offset != aligned_start {
+            let head = self
+                .read(&blob_client, name, aligned_start..aligned_start + PAGE_SIZE)
+                .await?;
+            pages[..PAGE_SIZE as usize].copy_from_slice(&head);
+        }
+        if end != aligned_end {
+            let tail = self
+                .read(&blob_client, name, aligned_end - PAGE_SIZE..aligned_end)
+                .await?;
+            let tail_start = pages.len() - PAGE_SIZE as usize;
+            pages[tail_start..].copy_from_slice(&tail);
+        }
+        let data_start = (offset - aligned_start) as usize;
+        pages[data_start..data_start + data.len()].copy_from_slice(data);
+
+        let mut position = aligned_start;
+        for chunk in pages.chunks(MAX_PUT_PAGE as usize) {
+            let length = chunk.len() as u64;
+            page_client
+                .upload_page(
+                    RequestContent::from(chunk.to_vec()),
+                    length,
+                    format_page_range(position, length)?,
+                    None,
+                )
+                .await
+                .context(format!("Failed to put pages to blob: {name}"))?;
+            position += length;
+        }
+        self.insert(aligned_start..aligned_end);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page_ranges(ranges: &[Range<u64>]) -> PageRanges {
+        let mut pages = PageRanges::default();
+        for range in ranges {
+            pages.insert(range.clone());
+        }
+        pages
+    }
+
+    #[test]
+    fn insert_merges_overlapping_and_adjacent_ranges() {
+        let mut pages = page_ranges(&[2048..2560, 0..512]);
+        assert_eq!(pages.ranges, vec![0..512, 2048..2560]);
+        pages.insert(512..1024);
+        assert_eq!(pages.ranges, vec![0..1024, 2048..2560]);
+        pages.insert(768..2048);
+        assert_eq!(pages.ranges, vec![0..2560]);
+    }
+
+    #[test]
+    fn truncate_drops_and_shortens_ranges() {
+        let mut pages = page_ranges(&[0..512, 1024..2048, 4096..4608]);
+        pages.truncate(1536);
+        assert_eq!(pages.ranges, vec![0..512, 1024..1536]);
+        pages.truncate(0);
+        assert!(pages.ranges.is_empty());
+    }
+
+    #[test]
+    fn sparse_reads_only_download_valid_parts() {
+        let pages = page_ranges(&[512..1024, 2048..2560]);
+        assert_eq!(pages.valid_parts(&(0..3072)), vec![512..1024, 2048..2560]);
+        assert_eq!(pages.valid_parts(&(768..2304)), vec![768..1024, 2048..2304]);
+        assert!(pages.valid_parts(&(1024..2048)).is_empty());
+    }
+
+    #[test]
+    fn sparse_regions_read_as_zeros() {
+        let range = 256..2304;
+        let pages = page_ranges(&[512..1024, 2048..2560]);
+        let parts = pages
+            .valid_parts(&range)
+            .into_iter()
+            .map(|part| {
+                let bytes = Bytes::from(vec![1; (part.end - part.start) as usize]);
+                (part, bytes)
+            })
+            .collect();
+        let data = PageRanges::zero_filled(&range, parts);
+
+        assert_eq!(data.len(), 2048);
+        let at = |offset: u64| data[(offset - range.start) as usize];
+        assert_eq!(at(256), 0);
+        assert_eq!(at(511), 0);
+        assert_eq!(at(512), 1);
+        assert_eq!(at(1023), 1);
+        assert_eq!(at(1024), 0);
+        assert_eq!(at(2047), 0);
+        assert_eq!(at(2048), 1);
+        assert_eq!(at(2303), 1);
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::{Context as _, Result};
+use azure_core::credentials::TokenCredential;
+use azure_core::http::headers::{ETAG, HeaderName};
+use azure_core::http::policies::{BearerTokenCredentialPolicy, Policy};
+use azure_core::http::{ClientOptions, Context, Method, Pipeline, RawResponse, Request, Url};
+use azure_core::time::OffsetDateTime;
+use azure_core::{base64, xml};
+use azure_storage_blob::models::BlobType;
+use log::info;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::listing::{DirectoryListing, ListedBlob, has_folder_metadata, modification_time};
+
+const STORAGE_SCOPE: &str = "https://storage.azure.com/.default";
+const STORAGE_VERSION: &str = "2025-11-05";
+const COPY_STATUS: HeaderName = HeaderName::from_static("x-ms-copy-status");
+const COPY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Response body of Get Page Ranges
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PageList {
+    #[serde(default)]
+    page_range: Vec<PageRange>,
+    next_marker: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PageRange {
+    start: u64,
+    end: u64,
+}
+
+/// Response body of List Blobs
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct EnumerationResults {
+    blobs: BlobList,
+    next_marker: Option<String>,
+}
+
+/// Blobs and prefixes are interleaved in name order
+#[derive(Debug, Deserialize)]
+struct BlobList {
+    #[serde(rename = "$value", default)]
+    items: Vec<BlobListItem>,
+}
+
+#[derive(Debug, Deserialize)]
+enum BlobListItem {
+    Blob(ListedBlobItem),
+    BlobPrefix(ListedPrefix),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedBlobItem {
+    name: String,
+    properties: ListedProperties,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListedProperties {
+    #[serde(rename = "Content-Length")]
+    content_length: u64,
+    #[serde(rename = "Last-Modified", with = "azure_core::time::rfc7231")]
+    last_modified: OffsetDateTime,
+    #[serde(rename = "Etag")]
+    etag: Option<String>,
+    #[serde(rename = "BlobType")]
+    blob_type: BlobType,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ListedPrefix {
+    name: String,
+}
+
+/// Issues Blob service REST calls that `azure_storage_blob` does not expose yet
+pub struct BlobRestClient {
+    container_url: Url,
+    pipeline: Pipeline,
+    credential: Arc<dyn TokenCredential>,
+}
+
+impl BlobRestClient {
+    /// Creates a new client for the given container, authenticated with Entra ID
+    pub fn new(
+        endpoint: &str,
+        container_name: &str,
+        credential: Arc<dyn TokenCredential>,
+    ) -> Result<Self> {
+        let container_url = Url::parse(endpoint)?.join(container_name)?;
+        let auth_policy: Arc<dyn Policy> = Arc::new(BearerTokenCredentialPolicy::new(
+            credential.clone(),
+            vec![STORAGE_SCOPE],
+        ));
+        let pipeline = Pipeline::new(
+            option_env!("CARGO_PKG_NAME"),
+            option_env!("CARGO_PKG_VERSION"),
+            ClientOptions::default(),
+            Vec::default(),
+            vec![auth_policy],
+        );
+        Ok(Self {
+            container_url,
+            pipeline,
+            credential,
+        })
+    }
+
+    /// Returns the URL of a blob in the container
+    pub fn blob_url(&self, blob_name: &str) -> Result<Url> {
+        let mut url = self.container_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::format_err!("Invalid container URL: {}", self.container_url))?
+            .pop_if_empty()
+            .extend(blob_name.split('/'));
+        Ok(url)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        headers: &[(&str, String)],
+    ) -> Result<RawResponse> {
+        let mut request = Request::new(url, method);
+        request.insert_header("x-ms-version", STORAGE_VERSION);
+        for (name, value) in headers {
+            request.insert_header(name.to_string(), value.clone());
+        }
+        Ok(self.pipeline.send(&Context::new(), &mut request).await?)
+    }
+
+    /// Copies a blob server-side with Copy Blob, waiting until the copy completes.
+    ///
+    /// Returns the ETag of the destination blob.
+    pub async fn copy_blob(&self, source: &str, destination: &str) -> Result<Option<String>> {
+        let source_url = self.blob_url(source)?;
+        let destination_url = self.blob_url(destination)?;
+        info!("Copying blob: {source} -> {destination}");
+
+        let response = self
+            .send(
+                Method::Put,
+                destination_url.clone(),
+                &[("x-ms-copy-source", source_url.to_string())],
+            )
+            .await
+            .context(format!("Failed to copy blob {source} to {destination}"))?;
+        let mut status = response.headers().get_optional_string(&COPY_STATUS);
+        let mut etag = response.headers().get_optional_string(&ETAG);
+
+        // Copies within an account are usually synchronous, poll the rest
+        while status.as_deref() == Some("pending") {
+            tokio::time::sleep(COPY_POLL_INTERVAL).await;
+            let response = self
+                .send(Method::Head, destination_url.clone(), &[])
+                .await
+                .context(format!("Failed to get copy status for blob: {destination}"))?;
+            status = response.headers().get_optional_string(&COPY_STATUS);
+            etag = response.headers().get_optional_string(&ETAG);
+        }
+
+        match status.as_deref() {
+            Some("success") | None => Ok(etag),
+            Some(status) => Err(anyhow::format_err!(
+                "Copy of blob {source} to {destination} ended with status: {status}"
+            )),
+        }
+    }
+
+    /// Stages a range of a blob as a new block of the same blob with Put Block From URL
+    pub async fn stage_block_from_url(
+        &self,
+        blob_name: &str,
+        block_id: &[u8],
+        range: Range<u64>,
+    ) -> Result<()> {
+        let source_url = self.blob_url(blob_name)?;
+        let mut url = source_url.clone();
+        url.query_pairs_mut()
+            .append_pair("comp", "block")
+            .append_pair("blockid", &base64::encode(block_id));
+        let token = self.credential.get_token(&[STORAGE_SCOPE], None).await?;
+
+        self.send(
+            Method::Put,
+            url,
+            &[
+                ("x-ms-copy-source", source_url.to_string()),
+                (
+                    "x-ms-source-range",
+                    format!("bytes={}-{}", range.start, range.end - 1),
+                ),
+                (
+                    "x-ms-copy-source-authorization",
+                    format!("Bearer {}", token.token.secret()),
+                ),
+            ],
+        )
+        .await
+        .context(format!("Failed to stage block from blob: {blob_name}"))?;
+        Ok(())
+    }
+
+    /// Lists the valid page ranges of a page blob with Get Page Ranges
+    pub async fn get_page_ranges(&self, blob_name: &str) -> Result<Vec<Range<u64>>> {
+        let mut ranges = Vec::new();
+        let mut marker: Option<String> = None;
+        loop {
+            let mut url = self.blob_url(blob_name)?;
+            url.query_pairs_mut().append_pair("comp", "pagelist");
+            if let Some(marker) = &marker {
+                url.query_pairs_mut().append_pair("marker", marker);
+            }
+            let body = self
+                .send(Method::Get, url, &[])
+                .await
+                .context(format!("Failed to get page ranges for blob: {blob_name}"))?
+                .into_body()
+                .collect()
+                .await?;
+            let page_list: PageList = xml::read_xml(&body)?;
+            // Page range ends are inclusive
+            ranges.extend(
+                page_list
+                    .page_range
+                    .into_iter()
+                    .map(|range| range.start..range.end + 1),
+            );
+            marker = page_list.next_marker.filter(|marker| !marker.is_empty());
+            if marker.is_none() {
+                return Ok(ranges);
+            }
+        }
+    }
+
+    /// Lists the blobs under a prefix with List Blobs, including their metadata.
+    ///
+    /// With a delimiter, deeper blobs are grouped into directories. Listing stops
+    /// once `max_results` entries were returned, if given.
+    pub async fn list_blobs(
+        &self,
+        prefix: &str,
+        delimiter: Option<&str>,
+        max_results: Option<u32>,
+    ) -> Result<DirectoryListing> {
+        let mut listing = DirectoryListing {
+            blobs: Vec::new(),
+            directories: Vec::new(),
+            started: SystemTime::now(),
+        };
+        let mut marker: Option<String> = None;
+        loop {
+            let mut url = self.container_url.clone();
+            url.query_pairs_mut()
+                .append_pair("restype", "container")
+                .append_pair("comp", "list")
+                .append_pair("include", "metadata")
+                .append_pair("prefix", prefix);
+            if let Some(delimiter) = delimiter {
+                url.query_pairs_mut().append_pair("delimiter", delimiter);
+            }
+            if let Some(max_results) = max_results {
+                url.query_pairs_mut()
+                    .append_pair("maxresults", &max_results.to_string());
+            }
+            if let Some(marker) = &marker {
+                url.query_pairs_mut().append_pair("marker", marker);
+            }
+            let body = self
+                .send(Method::Get, url, &[])
+                .await
+                .context(format!("Failed to list blobs under: {prefix}"))?
+                .into_body()
+                .collect()
+                .await?;
+            let results: EnumerationResults = xml::read_xml(&body)?;
+            for item in results.blobs.items {
+                match item {
+                    // A "dir/" marker blob is listed under its own prefix
+                    BlobListItem::Blob(blob) if blob.name == prefix => {}
+                    BlobListItem::Blob(blob) => {
+                        let marker =
+                            blob.name.ends_with('/') || has_folder_metadata(&blob.metadata);
+                        listing.blobs.push(ListedBlob {
+                            name: blob.name,
+                            size: blob.properties.content_length,
+                            last_modified: modification_time(
+                                &blob.metadata,
+                                blob.properties.last_modified,
+                            ),
+                            etag: blob.properties.etag,
+                            blob_type: blob.properties.blob_type,
+                            marker: marker && blob.properties.content_length == 0,
+                        });
+                    }
+                    BlobListItem::BlobPrefix(directory) => listing
+                        .directories
+                        .push(directory.name.trim_end_matches('/').to_string()),
+                }
+            }
+            marker = results.next_marker.filter(|marker| !marker.is_empty());
+            let returned = (listing.blobs.len() + listing.directories.len()) as u32;
+            if marker.is_none() || max_results.is_some_and(|max| returned >= max) {
+                return Ok(listing);
+            }
+        }
+    }
+}
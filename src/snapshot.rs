@@ -0,0 +1,214 @@
+use anyhow::{Context, Result};
+use azure_storage_blob::models::BlobType;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Bumped whenever the layout changes, so older snapshots are ignored
+const SNAPSHOT_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    pub name: String,
+    pub inode: u64,
+    pub size: u64,
+    pub last_modified: SystemTime,
+    pub etag: Option<String>,
+    pub blob_type: BlobType,
+    #[serde(default)]
+    pub generation: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotDirectory {
+    pub name: String,
+    pub inode: u64,
+    pub marker: Option<String>, // Name of the directory's marker blob, if it has one
+    #[serde(default)]
+    pub generation: u64,
+    #[serde(default)]
+    pub modified: Option<SystemTime>,
+}
+
+/// The namespace of a container as of the last mount, to mount again without listing first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub generation: u64, // Next generation handed out, bumped whenever an inode number is freed
+    #[serde(default)]
+    pub first_mounted: Option<SystemTime>, // When the namespace was first listed
+    pub directories: Vec<SnapshotDirectory>,
+    pub files: Vec<SnapshotFile>,
+}
+
+impl Snapshot {
+    pub fn new(
+        generation: u64,
+        first_mounted: SystemTime,
+        directories: Vec<SnapshotDirectory>,
+        files: Vec<SnapshotFile>,
+    ) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            generation,
+            first_mounted: Some(first_mounted),
+            directories,
+            files,
+        }
+    }
+
+    /// Reads a snapshot, returning None if there is none or it can't be used
+    pub fn load(path: &Path) -> Option<Self> {
+        let file = match fs::File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+            Err(err) => {
+                warn!("Failed to open snapshot {}: {err}", path.display());
+                return None;
+            }
+        };
+        match serde_json::from_reader::<_, Self>(BufReader::new(file)) {
+            Ok(snapshot) if snapshot.version == SNAPSHOT_VERSION => {
+                info!(
+                    "Loaded snapshot {} with {} files and {} directories",
+                    path.display(),
+                    snapshot.files.len(),
+                    snapshot.directories.len()
+                );
+                Some(snapshot)
+            }
+            Ok(snapshot) => {
+                warn!(
+                    "Ignoring snapshot {} of version {}",
+                    path.display(),
+                    snapshot.version
+                );
+                None
+            }
+            Err(err) => {
+                warn!("Ignoring unreadable snapshot {}: {err}", path.display());
+                None
+            }
+        }
+    }
+
+    /// Writes the snapshot under a temporary name and renames it into place
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context(format!(
+                "Failed to create snapshot directory: {}",
+                dir.display()
+            ))?;
+        }
+        let temporary = path.with_extension("tmp");
+        let file = fs::File::create(&temporary).context(format!(
+            "Failed to create snapshot: {}",
+            temporary.display()
+        ))?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self)
+            .map_err(io::Error::from)
+            .and_then(|()| writer.flush())
+            .context(format!("Failed to write snapshot: {}", temporary.display()))?;
+        fs::rename(&temporary, path)
+            .context(format!("Failed to replace snapshot: {}", path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    /// Snapshot path in a directory of its own, removed when dropped
+    struct TestPath(PathBuf);
+
+    impl TestPath {
+        fn new(test: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("snapshot-{test}-{}", std::process::id()));
+            Self(dir.join("container.json"))
+        }
+    }
+
+    impl Drop for TestPath {
+        fn drop(&mut self) {
+            if let Some(dir) = self.0.parent() {
+                let _ = fs::remove_dir_all(dir);
+            }
+        }
+    }
+
+    fn snapshot() -> Snapshot {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        Snapshot::new(
+            7,
+            time,
+            vec![SnapshotDirectory {
+                name: "dir".to_string(),
+                inode: 10,
+                marker: Some("dir/".to_string()),
+                generation: 3,
+                modified: Some(time),
+            }],
+            vec![SnapshotFile {
+                name: "dir/file".to_string(),
+                inode: 11,
+                size: 42,
+                last_modified: time,
+                etag: Some("0x8DC".to_string()),
+                blob_type: BlobType::BlockBlob,
+                generation: 0,
+            }],
+        )
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = TestPath::new("round-trip");
+        snapshot().save(&path.0).unwrap();
+        assert!(!path.0.with_extension("tmp").exists());
+
+        let loaded = Snapshot::load(&path.0).unwrap();
+        let expected = snapshot();
+        assert_eq!(loaded.generation, expected.generation);
+        assert_eq!(loaded.first_mounted, expected.first_mounted);
+        assert_eq!(loaded.directories.len(), 1);
+        let directory = &loaded.directories[0];
+        assert_eq!(directory.name, "dir");
+        assert_eq!(directory.inode, 10);
+        assert_eq!(directory.marker.as_deref(), Some("dir/"));
+        assert_eq!(directory.generation, 3);
+        assert_eq!(directory.modified, expected.directories[0].modified);
+        assert_eq!(loaded.files.len(), 1);
+        let file = &loaded.files[0];
+        assert_eq!(file.name, "dir/file");
+        assert_eq!(file.inode, 11);
+        assert_eq!(file.size, 42);
+        assert_eq!(file.last_modified, expected.files[0].last_modified);
+        assert_eq!(file.etag.as_deref(), Some("0x8DC"));
+        assert_eq!(file.blob_type, BlobType::BlockBlob);
+    }
+
+    #[test]
+    fn other_versions_are_ignored() {
+        let path = TestPath::new("version");
+        let mut snapshot = snapshot();
+        snapshot.version = SNAPSHOT_VERSION - 1;
+        snapshot.save(&path.0).unwrap();
+        assert!(Snapshot::load(&path.0).is_none());
+    }
+
+    #[test]
+    fn missing_or_unreadable_snapshots_are_ignored() {
+        let path = TestPath::new("unreadable");
+        assert!(Snapshot::load(&path.0).is_none());
+        fs::create_dir_all(path.0.parent().unwrap()).unwrap();
+        fs::write(&path.0, b"{\"version\":").unwrap();
+        assert!(Snapshot::load(&path.0).is_none());
+    }
+}